@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use meurglys3_lib::{LoadOptions, PackageReader};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(reader) = PackageReader::new(Cursor::new(data), &LoadOptions::default()) else {
+        return;
+    };
+    let names: Vec<String> = reader.names().map(String::from).collect();
+    for name in names {
+        let _ = reader.try_get_data(&name);
+        let _ = reader.link_target(&name);
+        if let Ok(Some(mut entry)) = reader.open_entry(&name) {
+            let _ = std::io::copy(&mut entry, &mut std::io::sink());
+        }
+    }
+});
@@ -1,7 +1,14 @@
+use std::error::Error;
+use std::io::Write;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use clap::Parser;
-use meurglys3_lib::{self, Package};
+use meurglys3_lib::err::{EncryptionError, PackingError, UnpackError, WriteError};
+use meurglys3_lib::{
+    self, Compression, Encryption, EncryptionKey, LoadOptions, PackOptions, Package, PackageWriter,
+    ProgressSink, SigningKey, SymlinkPolicy, UnpackOptions, VerifyingKey, WriteOptions,
+};
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -11,6 +18,9 @@ use meurglys3_lib::{self, Package};
     long_about = "Packages whole directories (including subdirectories) into a single .m3pkg file while preserving the directory structure for later unpacking."
 )]
 #[command(author = "Adam Papieros")]
+#[command(
+    after_help = "Exit codes: 1 other failure, 2 usage error, 3 I/O error, 4 corrupt package, 5 verification failure"
+)]
 struct Args {
     #[command(subcommand)]
     target: Target,
@@ -34,6 +44,67 @@ enum Target {
         dir: PathBuf,
         #[arg(help = "output file name")]
         out: PathBuf,
+        #[arg(
+            short,
+            long,
+            value_enum,
+            default_value_t = CompressionArg::None,
+            help = "compression applied to the packaged files"
+        )]
+        compression: CompressionArg,
+        #[arg(
+            short,
+            long,
+            default_value_t = 0,
+            allow_negative_numbers = true,
+            help = "compression level, 0 selects the default level of the codec"
+        )]
+        level: i32,
+        #[command(flatten)]
+        key: KeyArgs,
+        #[arg(
+            long,
+            requires = "encryption",
+            help = "also encrypt the file names of an encrypted package"
+        )]
+        encrypt_names: bool,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = SymlinkArg::Follow,
+            help = "how symbolic links inside of the directory are packaged"
+        )]
+        symlinks: SymlinkArg,
+        #[arg(
+            long,
+            env = "SOURCE_DATE_EPOCH",
+            allow_negative_numbers = true,
+            help = "store modification times later than this Unix timestamp as the timestamp"
+        )]
+        clamp_mtime: Option<i64>,
+        #[arg(long, help = "keep the package being replaced as <OUT>.bak")]
+        backup: bool,
+        #[arg(
+            long,
+            value_name = "PATTERN",
+            help = "only package files matching the pattern, can be given multiple times"
+        )]
+        include: Vec<String>,
+        #[arg(
+            long,
+            value_name = "PATTERN",
+            help = "leave out files matching the pattern in addition to the .m3ignore files, can be given multiple times"
+        )]
+        exclude: Vec<String>,
+        #[arg(
+            short = 'j',
+            long,
+            default_value_t = 0,
+            help = "number of threads reading and compressing files, 0 uses one per CPU core"
+        )]
+        threads: usize,
+        #[arg(long, help = "show a progress bar while packaging")]
+        progress: bool,
     },
     #[command(about = "Unpackage a directory", long_about = None)]
     Unpack {
@@ -41,6 +112,24 @@ enum Target {
         dir: PathBuf,
         #[arg(help = "output directory path")]
         out: PathBuf,
+        #[command(flatten)]
+        key: KeyArgs,
+        #[arg(
+            short,
+            long,
+            help = "restore the permissions and modification times of the packaged files"
+        )]
+        preserve: bool,
+        #[arg(long, help = "restore the owner and group of the packaged files")]
+        same_owner: bool,
+        #[arg(
+            long,
+            requires_all = ["preserve", "same_owner"],
+            help = "also restore the setuid, setgid and sticky bits, only for trusted packages"
+        )]
+        special_bits: bool,
+        #[arg(long, help = "show a progress bar while unpacking")]
+        progress: bool,
     },
     #[command(about = "Check wether a package contains a file", long_about = None)]
     Check {
@@ -51,49 +140,422 @@ enum Target {
         )]
         #[clap(value_parser, num_args = 1.., value_delimiter = ',')]
         check: Vec<String>,
+        #[command(flatten)]
+        key: KeyArgs,
     },
     #[command(about = "List contained files", long_about = None)]
     List {
         #[arg(help = "source .m3pkg file")]
         dir: PathBuf,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    #[command(about = "Generate a key pair for signing packages", long_about = None)]
+    Keygen {
+        #[arg(
+            help = "output path, the signing key is written with a .key and the verifying key with a .pub extension"
+        )]
+        out: PathBuf,
+    },
+    #[command(about = "Sign a package", long_about = None)]
+    Sign {
+        #[arg(help = "source .m3pkg file")]
+        dir: PathBuf,
+        #[arg(short, long, help = "signing key file")]
+        key: PathBuf,
     },
+    #[command(about = "Verify the signature and checksums of a package", long_about = None)]
+    Verify {
+        #[arg(help = "source .m3pkg file")]
+        dir: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "a comma separated list of trusted verifying key files"
+        )]
+        #[clap(value_parser, num_args = 1.., value_delimiter = ',', required = true)]
+        keys: Vec<PathBuf>,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+#[group(id = "encryption", multiple = false)]
+struct KeyArgs {
+    #[arg(long, help = "passphrase the package is encrypted with")]
+    password: Option<String>,
+    #[arg(
+        long,
+        help = "file containing the raw 32 byte key the package is encrypted with"
+    )]
+    key_file: Option<PathBuf>,
+}
+impl KeyArgs {
+    fn key(&self) -> Result<Option<EncryptionKey>, CliError> {
+        if let Some(password) = &self.password {
+            return Ok(Some(EncryptionKey::Passphrase(password.clone())));
+        }
+        match &self.key_file {
+            Some(path) => Ok(Some(EncryptionKey::Raw(read_key(path)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum CompressionArg {
+    None,
+    Zstd,
+    Deflate,
+    Lz4,
+}
+impl From<CompressionArg> for Compression {
+    fn from(value: CompressionArg) -> Self {
+        match value {
+            CompressionArg::None => Compression::None,
+            CompressionArg::Zstd => Compression::Zstd,
+            CompressionArg::Deflate => Compression::Deflate,
+            CompressionArg::Lz4 => Compression::Lz4,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum SymlinkArg {
+    /// package the files links point to
+    Follow,
+    /// store links as links
+    Link,
+    /// leave links out
+    Skip,
+}
+impl From<SymlinkArg> for SymlinkPolicy {
+    fn from(value: SymlinkArg) -> Self {
+        match value {
+            SymlinkArg::Follow => SymlinkPolicy::Follow,
+            SymlinkArg::Link => SymlinkPolicy::Link,
+            SymlinkArg::Skip => SymlinkPolicy::Skip,
+        }
+    }
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
+    match run(args.target) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e.message);
+            let mut source = e.source.as_deref();
+            while let Some(cause) = source {
+                eprintln!("  caused by: {cause}");
+                source = cause.source();
+            }
+            ExitCode::from(e.failure as u8)
+        }
+    }
+}
 
-    match args.target {
-        Target::Pack { dir, out } => {
-            let mut pack = meurglys3_lib::package_dir(dir).expect("Failed to package");
-            meurglys3_lib::write_package(out, &mut pack).expect("failed to write package");
+/// What kind of failure ended the program, its value being the exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    Other = 1,
+    /// Invalid arguments, the exit code clap uses as well
+    Usage = 2,
+    Io = 3,
+    /// The package is malformed or of an unsupported format
+    Corrupt = 4,
+    /// The signature, a checksum or the encryption of the package does not check out
+    Verification = 5,
+}
+
+impl Failure {
+    fn of_unpack(e: &UnpackError) -> Self {
+        match e {
+            UnpackError::IoError(_) | UnpackError::FileError { .. } => Failure::Io,
+            UnpackError::InvalidFile { .. }
+            | UnpackError::ParseError(_)
+            | UnpackError::StringError { .. }
+            | UnpackError::LinkTargetError { .. }
+            | UnpackError::DecompressionError { .. }
+            | UnpackError::UnsupportedFormat(_)
+            | UnpackError::UnsafePath { .. }
+            | UnpackError::LimitExceeded(_)
+            // the key derivation parameters come from the package
+            | UnpackError::EncryptionError(EncryptionError::KeyDerivation) => Failure::Corrupt,
+            UnpackError::DecryptionError { .. }
+            | UnpackError::TableDecryptionError
+            | UnpackError::BadSignature
+            | UnpackError::ChecksumMismatch { .. } => Failure::Verification,
+            UnpackError::KeyRequired
+            | UnpackError::EncryptionError(
+                EncryptionError::RawKeyRequired | EncryptionError::PassphraseRequired,
+            ) => Failure::Usage,
+            UnpackError::EncryptionError(EncryptionError::Encryption) | UnpackError::Cancelled => {
+                Failure::Other
+            }
         }
-        Target::Unpack { dir, out } => {
-            unpack(dir, out);
+    }
+    fn of_write(e: &WriteError) -> Self {
+        match e {
+            WriteError::IncompleteWrite { .. }
+            | WriteError::ReadError { .. }
+            | WriteError::IoError(_)
+            | WriteError::PackingError(PackingError::IoError { .. }) => Failure::Io,
+            _ => Failure::Other,
         }
-        Target::Check { dir, check } => {
-            let pack = meurglys3_lib::load_package(dir.clone()).unwrap_or_else(|_| {
-                panic!(
-                    "could not load package at `{}`",
-                    dir.to_str().unwrap_or_default()
-                )
+    }
+}
+
+/// An error ending the program, printed along with the errors that caused it
+struct CliError {
+    failure: Failure,
+    message: String,
+    source: Option<Box<dyn Error>>,
+}
+
+impl CliError {
+    fn new(failure: Failure, message: impl Into<String>) -> Self {
+        Self {
+            failure,
+            message: message.into(),
+            source: None,
+        }
+    }
+    fn caused_by(mut self, source: impl Error + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+    fn io(message: impl Into<String>, e: std::io::Error) -> Self {
+        Self::new(Failure::Io, message).caused_by(e)
+    }
+    fn unpack(message: impl Into<String>, e: UnpackError) -> Self {
+        Self::new(Failure::of_unpack(&e), message).caused_by(e)
+    }
+    fn write(message: impl Into<String>, e: WriteError) -> Self {
+        Self::new(Failure::of_write(&e), message).caused_by(e)
+    }
+}
+
+fn run(target: Target) -> Result<(), CliError> {
+    match target {
+        Target::Pack {
+            dir,
+            out,
+            compression,
+            level,
+            key,
+            encrypt_names,
+            symlinks,
+            clamp_mtime,
+            backup,
+            include,
+            exclude,
+            threads,
+            progress,
+        } => {
+            let encryption = key.key()?.map(|key| Encryption {
+                key,
+                encrypt_table: encrypt_names,
             });
+            let options = WriteOptions {
+                backup,
+                ..Default::default()
+            };
+            let mut writer = PackageWriter::create_with(
+                out.clone(),
+                compression.into(),
+                encryption,
+                &options,
+            )
+            .map_err(|e| CliError::write(format!("failed to create `{}`", out.display()), e))?;
+            writer.set_compression_level(level);
+            let options = PackOptions {
+                symlinks: symlinks.into(),
+                include,
+                exclude,
+                mtime_clamp: clamp_mtime,
+                threads,
+                ..Default::default()
+            };
+            let mut bar = ProgressBar::new(progress);
+            writer
+                .add_dir_with_progress(dir.clone(), &options, &mut bar)
+                .map_err(|e| {
+                    CliError::write(format!("failed to package `{}`", dir.display()), e)
+                })?;
+            drop(bar);
+            writer
+                .finish()
+                .map_err(|e| CliError::write(format!("failed to write `{}`", out.display()), e))?;
+        }
+        Target::Unpack {
+            dir,
+            out,
+            key,
+            preserve,
+            same_owner,
+            special_bits,
+            progress,
+        } => {
+            let options = UnpackOptions {
+                restore_metadata: preserve,
+                restore_ownership: same_owner,
+                restore_special_bits: special_bits,
+                ..Default::default()
+            };
+            let mut bar = ProgressBar::new(progress);
+            unpack(dir, out, &key, &options, &mut bar)?;
+        }
+        Target::Check { dir, check, key } => {
+            let pack = load(dir, &key)?;
             check_pack(&check, &pack)
         }
-        Target::List { dir } => {
-            let pack = meurglys3_lib::load_package(dir.clone()).unwrap_or_else(|_| {
-                panic!(
-                    "could not load package at `{}`",
-                    dir.to_str().unwrap_or_default()
-                )
-            });
+        Target::List { dir, key } => {
+            let pack = load(dir, &key)?;
             list_pack(&pack)
         }
+        Target::Keygen { out } => keygen(out)?,
+        Target::Sign { dir, key } => {
+            let key = SigningKey::from_bytes(&read_key(&key)?);
+            meurglys3_lib::sign_package(dir.clone(), &key)
+                .map_err(|e| CliError::write(format!("failed to sign `{}`", dir.display()), e))?;
+        }
+        Target::Verify { dir, keys, key } => {
+            let keys = keys
+                .iter()
+                .map(|k| {
+                    VerifyingKey::from_bytes(&read_key(k)?).map_err(|e| {
+                        let message = format!("`{}` is not a valid verifying key", k.display());
+                        CliError::new(Failure::Usage, message).caused_by(e)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            verify_pack(dir, &keys, &key)?
+        }
+    };
+    Ok(())
+}
+fn read_key(path: &PathBuf) -> Result<[u8; 32], CliError> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| CliError::io(format!("failed to read key file `{}`", path.display()), e))?;
+    bytes.try_into().map_err(|_| {
+        let message = format!(
+            "key file `{}` must contain exactly 32 bytes",
+            path.display()
+        );
+        CliError::new(Failure::Usage, message)
+    })
+}
+fn keygen(mut out: PathBuf) -> Result<(), CliError> {
+    let mut secret = [0u8; 32];
+    rand::fill(&mut secret);
+    let key = SigningKey::from_bytes(&secret);
+    let failed = |out: &PathBuf, e| CliError::io(format!("failed to write `{}`", out.display()), e);
+    out.set_extension("key");
+    // the signing key is only readable by its owner, and an existing one is never overwritten
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&out)
+        .and_then(|mut file| file.write_all(&key.to_bytes()))
+        .map_err(|e| failed(&out, e))?;
+    out.set_extension("pub");
+    std::fs::write(&out, key.verifying_key().to_bytes()).map_err(|e| failed(&out, e))?;
+    Ok(())
+}
+fn verify_pack(source: PathBuf, keys: &[VerifyingKey], key: &KeyArgs) -> Result<(), CliError> {
+    let options = LoadOptions {
+        key: key.key()?,
+        ..Default::default()
+    };
+    let pack = meurglys3_lib::load_package_verified_with(source.clone(), keys, &options)
+        .map_err(|e| CliError::unpack(format!("could not verify `{}`", source.display()), e))?;
+    let corrupted = pack.verify();
+    if !corrupted.is_empty() {
+        for c in &corrupted {
+            eprintln!("`{c}` does not match its checksum");
+        }
+        let message = format!("{} entries do not match their checksums", corrupted.len());
+        return Err(CliError::new(Failure::Verification, message));
+    }
+    println!("The package signature is valid");
+    Ok(())
+}
+fn load(source: PathBuf, key: &KeyArgs) -> Result<Package, CliError> {
+    let options = LoadOptions {
+        key: key.key()?,
+        ..Default::default()
     };
+    meurglys3_lib::load_package_with(source.clone(), &options).map_err(|e| {
+        CliError::unpack(
+            format!("could not load package at `{}`", source.display()),
+            e,
+        )
+    })
+}
+/// Shows the progress of an operation on stderr, or nothing when it is hidden
+struct ProgressBar {
+    bar: indicatif::ProgressBar,
+    entries: usize,
+    started: usize,
+}
+
+impl ProgressBar {
+    fn new(show: bool) -> Self {
+        let bar = match show {
+            true => {
+                let style = indicatif::ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} {wide_msg}",
+                )
+                .expect("invalid progress bar template");
+                indicatif::ProgressBar::new(0).with_style(style)
+            }
+            false => indicatif::ProgressBar::hidden(),
+        };
+        Self {
+            bar,
+            entries: 0,
+            started: 0,
+        }
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
 }
-fn unpack(source: PathBuf, dest: PathBuf) -> Package {
-    let pack = meurglys3_lib::load_package(source).expect("unpack failed");
-    meurglys3_lib::unpack_to_dir(dest, &pack).unwrap();
-    pack
+
+impl ProgressSink for ProgressBar {
+    fn total(&mut self, entries: usize, bytes: u64) {
+        self.entries = entries;
+        self.bar.set_length(bytes);
+    }
+    fn entry_started(&mut self, name: &str) {
+        self.started += 1;
+        let (started, entries) = (self.started, self.entries);
+        self.bar
+            .set_message(format!("[{started}/{entries}] {name}"));
+    }
+    fn bytes_processed(&mut self, bytes: u64) {
+        self.bar.inc(bytes);
+    }
+}
+
+fn unpack(
+    source: PathBuf,
+    dest: PathBuf,
+    key: &KeyArgs,
+    options: &UnpackOptions,
+    progress: &mut dyn ProgressSink,
+) -> Result<Package, CliError> {
+    let pack = load(source, key)?;
+    meurglys3_lib::unpack_to_dir_with_progress(dest.clone(), &pack, options, progress)
+        .map_err(|e| CliError::unpack(format!("failed to unpack into `{}`", dest.display()), e))?;
+    Ok(pack)
 }
 fn check_pack(names: &Vec<String>, pack: &Package) {
     for n in names {
@@ -106,6 +568,12 @@ fn check_pack(names: &Vec<String>, pack: &Package) {
 }
 fn list_pack(pack: &Package) {
     let mut files = pack.get_files().keys().cloned().collect::<Vec<_>>();
+    files.extend(
+        pack.links()
+            .iter()
+            .map(|(name, target)| format!("{name} -> {target}")),
+    );
+    files.extend(pack.directories().iter().map(|name| format!("{name}/")));
     files.sort();
     for f in files {
         println!("{f}");
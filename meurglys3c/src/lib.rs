@@ -1,6 +1,6 @@
-use libc::{c_char, c_uchar, c_ulonglong, c_void};
+use libc::{c_char, c_int, c_uchar, c_ulonglong, c_void};
 pub use meurglys3_lib::Compression;
-use meurglys3_lib::{self, Package};
+use meurglys3_lib::{self, CancellationToken, Package, ProgressSink, VerifyingKey};
 use std::ffi::{CStr, CString};
 use std::path::PathBuf;
 use std::ptr::{self, null_mut};
@@ -24,6 +24,10 @@ pub enum Error {
     PathError,
     /// Error with inserting data
     InsertError,
+    /// The package signature is missing or was not made by a trusted key
+    SignatureError,
+    /// The progress callback cancelled the operation
+    Cancelled,
 }
 #[repr(C)]
 pub struct PackageVersion {
@@ -39,6 +43,89 @@ pub struct FileList {
     pub data: *mut *mut c_char,
 }
 
+#[repr(C)]
+/// Options for packaging a directory, the patterns use the syntax of `.m3ignore` files
+pub struct PackOptions {
+    /// `include_len` patterns of the files to package, every file is packaged when there are none
+    pub include: *const *const c_char,
+    pub include_len: usize,
+    /// `exclude_len` patterns of the files and directories to leave out
+    pub exclude: *const *const c_char,
+    pub exclude_len: usize,
+    /// Called with the progress of packaging the directory, may be NULL
+    pub progress: ProgressCallback,
+    pub user_data: *mut c_void,
+}
+
+#[repr(C)]
+/// What a call of a `ProgressCallback` reports
+pub enum ProgressEvent {
+    /// `entries` entries with `bytes` bytes of data are going to be processed
+    Total,
+    /// Processing the entry `name` started
+    EntryStarted,
+    /// Another `bytes` bytes of the current entry were processed
+    BytesProcessed,
+    /// Processing the entry `name` finished
+    EntryFinished,
+}
+
+/// Called with the progress of an operation and the `user_data` given along with it. `name` is
+/// NULL unless the event is about an entry. Returning false cancels the operation, which then
+/// fails with `Cancelled`
+pub type ProgressCallback = Option<
+    unsafe extern "C" fn(
+        event: ProgressEvent,
+        name: *const c_char,
+        entries: usize,
+        bytes: c_ulonglong,
+        user_data: *mut c_void,
+    ) -> bool,
+>;
+
+/// Forwards progress to a C callback, cancelling the operation once the callback returns false
+struct CallbackProgress {
+    callback: ProgressCallback,
+    user_data: *mut c_void,
+    cancel: CancellationToken,
+}
+
+impl CallbackProgress {
+    fn new(callback: ProgressCallback, user_data: *mut c_void) -> Self {
+        Self {
+            callback,
+            user_data,
+            cancel: CancellationToken::new(),
+        }
+    }
+    fn report(&mut self, event: ProgressEvent, name: Option<&str>, entries: usize, bytes: u64) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        // entry names never contain NUL bytes
+        let name = name.map(|name| CString::new(name).unwrap_or_default());
+        let name = name.as_ref().map_or(ptr::null(), |name| name.as_ptr());
+        if !unsafe { callback(event, name, entries, bytes, self.user_data) } {
+            self.cancel.cancel();
+        }
+    }
+}
+
+impl ProgressSink for CallbackProgress {
+    fn total(&mut self, entries: usize, bytes: u64) {
+        self.report(ProgressEvent::Total, None, entries, bytes);
+    }
+    fn entry_started(&mut self, name: &str) {
+        self.report(ProgressEvent::EntryStarted, Some(name), 0, 0);
+    }
+    fn bytes_processed(&mut self, bytes: u64) {
+        self.report(ProgressEvent::BytesProcessed, None, 0, bytes);
+    }
+    fn entry_finished(&mut self, name: &str) {
+        self.report(ProgressEvent::EntryFinished, Some(name), 0, 0);
+    }
+}
+
 pub type PACKAGE = c_void;
 pub type BYTES = *mut c_uchar;
 
@@ -61,6 +148,52 @@ pub unsafe extern "C" fn meu3_package_dir(dir_path: &c_char, err: &mut Error) ->
     Box::into_raw(pack) as *mut c_void
 }
 #[no_mangle]
+/// Packages a directory like `meu3_package_dir`, leaving out files as `options` ask for.
+/// `.m3ignore` files inside of the directory are honored either way
+/// # Safety
+/// Internally this function does some pointer casting
+pub unsafe extern "C" fn meu3_package_dir_with(
+    dir_path: &c_char,
+    options: &PackOptions,
+    err: &mut Error,
+) -> *mut PACKAGE {
+    let path = CStr::from_ptr(dir_path as *const _);
+    let Ok(path) = path.to_str() else {
+        *err = Error::StringError;
+        return null_mut::<c_void>();
+    };
+    let patterns = c_strings(options.include, options.include_len)
+        .and_then(|include| Ok((include, c_strings(options.exclude, options.exclude_len)?)));
+    let (include, exclude) = match patterns {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            *err = e;
+            return null_mut::<c_void>();
+        }
+    };
+    let mut progress = CallbackProgress::new(options.progress, options.user_data);
+    let options = meurglys3_lib::PackOptions {
+        include,
+        exclude,
+        cancel: progress.cancel.clone(),
+        ..Default::default()
+    };
+    let path = std::path::PathBuf::from(path);
+    let pack = match meurglys3_lib::package_dir_with_progress(path, &options, &mut progress) {
+        Ok(pack) => pack,
+        Err(meurglys3_lib::err::PackingError::Cancelled) => {
+            *err = Error::Cancelled;
+            return null_mut::<c_void>();
+        }
+        Err(_) => {
+            *err = Error::PackError;
+            return null_mut::<c_void>();
+        }
+    };
+    let pack = Box::new(pack);
+    Box::into_raw(pack) as *mut c_void
+}
+#[no_mangle]
 /// Frees the package from memory, the pointer becomes invalid after this call
 pub extern "C" fn meu3_free_package(package: &mut PACKAGE) {
     let pack = unsafe { Box::from_raw(package as *mut c_void as *mut Package) };
@@ -84,6 +217,54 @@ pub unsafe extern "C" fn meu3_load_package(dir_path: &c_char, err: &mut Error) -
     Box::into_raw(pack) as *mut PACKAGE
 }
 #[no_mangle]
+/// Loads a package from a package file under the specified path only if it is signed by one of
+/// the trusted keys. `keys` points to `keys_len` verifying keys of 32 bytes each, stored one after
+/// another
+/// # Safety
+/// Internally this function does some pointer casting
+pub unsafe extern "C" fn meu3_load_package_verified(
+    dir_path: &c_char,
+    keys: *const c_uchar,
+    keys_len: usize,
+    err: &mut Error,
+) -> *mut PACKAGE {
+    let path = CStr::from_ptr(dir_path as *const _);
+    let Ok(path) = path.to_str() else {
+        *err = Error::StringError;
+        return null_mut();
+    };
+    if keys.is_null() && keys_len > 0 {
+        *err = Error::ParameterWasNull;
+        return null_mut();
+    }
+    let keys = if keys_len > 0 {
+        std::slice::from_raw_parts(keys, keys_len * 32)
+    } else {
+        &[]
+    };
+    let Ok(keys) = keys
+        .chunks_exact(32)
+        .map(|k| VerifyingKey::from_bytes(k.try_into().unwrap()))
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        *err = Error::SignatureError;
+        return null_mut();
+    };
+    let pack = match meurglys3_lib::load_package_verified(PathBuf::from(path), &keys) {
+        Ok(pack) => pack,
+        Err(meurglys3_lib::err::UnpackError::BadSignature) => {
+            *err = Error::SignatureError;
+            return null_mut();
+        }
+        Err(_) => {
+            *err = Error::PackError;
+            return null_mut();
+        }
+    };
+    let pack = Box::new(pack);
+    Box::into_raw(pack) as *mut PACKAGE
+}
+#[no_mangle]
 /// Unpacks the package object into a directory
 /// # Safety
 /// Internally this function does some pointer casting
@@ -113,6 +294,80 @@ pub unsafe extern "C" fn meu3_write_package(
     }
 }
 #[no_mangle]
+/// Writes the package object to a package file like `meu3_write_package`, calling `progress`
+/// with `user_data` for every entry written. `progress` may be NULL
+/// # Safety
+/// Internally this function does some pointer casting
+pub unsafe extern "C" fn meu3_write_package_with_progress(
+    path: &c_char,
+    package: &mut PACKAGE,
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+    err: &mut Error,
+) -> bool {
+    let pack = extract_mut_ref(package as *mut c_void as *mut Package);
+    let Ok(pack) = pack else {
+        *err = pack.unwrap_err();
+        return false;
+    };
+    let Ok(str) = CStr::from_ptr(path as *const _).to_str() else {
+        *err = Error::StringError;
+        return false;
+    };
+    let mut progress = CallbackProgress::new(progress, user_data);
+    let options = meurglys3_lib::WriteOptions {
+        cancel: progress.cancel.clone(),
+        ..Default::default()
+    };
+    let path = PathBuf::from(str);
+    match meurglys3_lib::write_package_with_progress(path, pack, &options, &mut progress) {
+        Ok(_) => true,
+        Err(meurglys3_lib::err::WriteError::Cancelled) => {
+            *err = Error::Cancelled;
+            false
+        }
+        Err(_e) => {
+            *err = Error::WritePackageError;
+            false
+        }
+    }
+}
+#[no_mangle]
+/// Unpacks the package object into the directory at `dir_path`, calling `progress` with
+/// `user_data` for every entry unpacked. `progress` may be NULL
+/// # Safety
+/// Internally this function does some pointer casting
+pub unsafe extern "C" fn meu3_unpack_package(
+    package: &PACKAGE,
+    dir_path: &c_char,
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+    err: &mut Error,
+) -> bool {
+    let pack = &*(package as *const PACKAGE as *const Package);
+    let Ok(str) = CStr::from_ptr(dir_path as *const _).to_str() else {
+        *err = Error::StringError;
+        return false;
+    };
+    let mut progress = CallbackProgress::new(progress, user_data);
+    let options = meurglys3_lib::UnpackOptions {
+        cancel: progress.cancel.clone(),
+        ..Default::default()
+    };
+    let path = PathBuf::from(str);
+    match meurglys3_lib::unpack_to_dir_with_progress(path, pack, &options, &mut progress) {
+        Ok(_) => true,
+        Err(meurglys3_lib::err::UnpackError::Cancelled) => {
+            *err = Error::Cancelled;
+            false
+        }
+        Err(_e) => {
+            *err = Error::PackError;
+            false
+        }
+    }
+}
+#[no_mangle]
 /// Check if a package contains a file with specified path
 /// # Safety
 /// Internally this function does some pointer casting
@@ -215,6 +470,35 @@ pub unsafe extern "C" fn meu3_package_get_compression(
     }
 }
 #[no_mangle]
+/// Set the compression algorithm and level applied when the package is written, a level of 0
+/// selects the default level of the algorithm. `compression` is one of the `MEU3_Compression`
+/// values, any other value fails with `FailedCast`
+/// # Safety
+/// Internally this function does some pointer casting
+pub unsafe extern "C" fn meu3_package_set_compression(
+    pack: &mut PACKAGE,
+    compression: c_int,
+    level: c_int,
+    err: &mut Error,
+) -> bool {
+    *err = Error::NoError;
+    let Some(compression) = compression_from_c(compression) else {
+        *err = Error::FailedCast;
+        return false;
+    };
+    match extract_mut_ref(pack as *mut c_void as *mut Package) {
+        Ok(pack) => {
+            pack.set_compression(compression);
+            pack.set_compression_level(level);
+            true
+        }
+        Err(e) => {
+            *err = e;
+            false
+        }
+    }
+}
+#[no_mangle]
 /// Remove a file from the package under the specified path
 /// # Safety
 /// Internally this function does some pointer casting
@@ -271,8 +555,7 @@ pub unsafe extern "C" fn meu3_package_get_file_list(
     let mut files = (*package)
         .get_files()
         .keys()
-        .cloned()
-        .map(|s| CString::from_str(&s).unwrap())
+        .map(|s| CString::from_str(s).unwrap())
         .collect::<Vec<_>>();
     files.sort();
     let mut files = files.into_iter().map(CString::into_raw).collect::<Vec<_>>();
@@ -299,6 +582,37 @@ pub unsafe extern "C" fn meu3_free_file_list(file_list: FileList) {
         .collect::<Vec<_>>();
     drop(files)
 }
+unsafe fn c_strings(strings: *const *const c_char, len: usize) -> Result<Vec<String>, Error> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    if strings.is_null() {
+        return Err(Error::ParameterWasNull);
+    }
+    std::slice::from_raw_parts(strings, len)
+        .iter()
+        .map(|s| {
+            if s.is_null() {
+                return Err(Error::ParameterWasNull);
+            }
+            let s = CStr::from_ptr(*s)
+                .to_str()
+                .map_err(|_| Error::StringError)?;
+            Ok(s.to_string())
+        })
+        .collect()
+}
+/// Checks a compression passed from C, where an enum may hold any value
+fn compression_from_c(value: c_int) -> Option<Compression> {
+    [
+        Compression::None,
+        Compression::Zstd,
+        Compression::Deflate,
+        Compression::Lz4,
+    ]
+    .into_iter()
+    .find(|compression| *compression as c_int == value)
+}
 unsafe fn extract_mut_ref<'a, T>(val: *mut T) -> Result<&'a mut T, Error> {
     if val.is_null() {
         return Err(Error::ParameterWasNull);
@@ -0,0 +1,46 @@
+#include "meu3.h"
+
+int main(void) {
+    MEU3_Error err = -1;
+    MEU3_Compression compressions[] = { Zstd, Deflate, Lz4 };
+    for(size_t i = 0; i < sizeof(compressions) / sizeof(compressions[0]); i++){
+        MEU3_PACKAGE* pack = meu3_package_dir("test_dir", &err);
+        if(!pack) {
+            return 1;
+        }
+        bool res = meu3_package_set_compression(pack, compressions[i], 0, &err);
+        if(!res || err != NoError)
+            return 1;
+        res = meu3_write_package("dump/comppack", pack, &err);
+        if(!res || err != NoError)
+            return 1;
+        MEU3_PACKAGE* pack2 = meu3_load_package("dump/comppack.m3pkg", &err);
+        if(!pack2)
+            return 1;
+        MEU3_Compression comp = meu3_package_get_compression(pack2, &err);
+        if(comp != compressions[i] || err != NoError)
+            return 1;
+        unsigned long long len = 0;
+        MEU3_BYTES data = meu3_package_get_data_ptr(pack2, "text.txt", &len, &err);
+        unsigned long long orig_len = 0;
+        MEU3_BYTES orig = meu3_package_get_data_ptr(pack, "text.txt", &orig_len, &err);
+        if(!data || !orig || len != orig_len || err != NoError)
+            return 1;
+        for(size_t j = 0; j < len; j++){
+            if(data[j] != orig[j])
+                return 1;
+        }
+        meu3_free_package(pack);
+        meu3_free_package(pack2);
+    }
+    MEU3_PACKAGE* pack = meu3_package_dir("test_dir", &err);
+    if(!pack)
+        return 1;
+    // values outside of the enum are rejected instead of being used
+    if(meu3_package_set_compression(pack, 7, 0, &err) || err != FailedCast)
+        return 1;
+    if(meu3_package_get_compression(pack, &err) != None || err != NoError)
+        return 1;
+    meu3_free_package(pack);
+    return 0;
+}
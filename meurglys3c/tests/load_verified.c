@@ -0,0 +1,25 @@
+#include "meu3.h"
+
+int main(void) {
+    MEU3_Error err = -1;
+    MEU3_PACKAGE* pack = meu3_package_dir("test_dir", &err);
+    if(!pack) {
+        return 1;
+    }
+    bool res = meu3_write_package("dump/unsigned", pack, &err);
+    if(!res || err != NoError)
+        return 1;
+    unsigned char keys[32 * 2] = { 0 };
+    // keys that never signed anything, the package is not signed at all
+    keys[0] = 1;
+    keys[32] = 1;
+    MEU3_PACKAGE* pack2 = meu3_load_package_verified("dump/unsigned.m3pkg", keys, 2, &err);
+    if(pack2 || err != SignatureError)
+        return 1;
+    err = NoError;
+    pack2 = meu3_load_package_verified("dump/unsigned.m3pkg", NULL, 0, &err);
+    if(pack2 || err != SignatureError)
+        return 1;
+    meu3_free_package(pack);
+    return 0;
+}
@@ -0,0 +1,30 @@
+#include "meu3.h"
+#include "string.h"
+
+int main(void) {
+    MEU3_Error err = -1;
+    const char* exclude[] = { "*.html" };
+    struct MEU3_PackOptions options = { NULL, 0, exclude, 1 };
+    MEU3_PACKAGE* pack = meu3_package_dir_with("test_dir", &options, &err);
+    if(!pack) {
+        return 1;
+    }
+    struct MEU3_FileList flist = meu3_package_get_file_list(pack, &err);
+    if(flist.len != 1 || strcmp(flist.data[0], "text.txt") != 0)
+        return 1;
+    meu3_free_file_list(flist);
+    meu3_free_package(pack);
+
+    const char* include[] = { "nested/" };
+    struct MEU3_PackOptions include_options = { include, 1, NULL, 0 };
+    pack = meu3_package_dir_with("test_dir", &include_options, &err);
+    if(!pack) {
+        return 1;
+    }
+    flist = meu3_package_get_file_list(pack, &err);
+    if(flist.len != 1 || strcmp(flist.data[0], "nested/index.html") != 0)
+        return 1;
+    meu3_free_file_list(flist);
+    meu3_free_package(pack);
+    return 0;
+}
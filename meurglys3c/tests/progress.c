@@ -0,0 +1,71 @@
+#include "meu3.h"
+#include "string.h"
+
+struct Counts {
+    size_t entries;
+    unsigned long long bytes;
+    size_t started;
+    size_t finished;
+    unsigned long long processed;
+    size_t cancel_after;
+};
+
+bool count(MEU3_ProgressEvent event, const char* name, size_t entries, unsigned long long bytes, void* user_data) {
+    struct Counts* counts = user_data;
+    switch(event) {
+        case Total:
+            counts->entries = entries;
+            counts->bytes = bytes;
+            break;
+        case EntryStarted:
+            if(!name)
+                return false;
+            counts->started++;
+            break;
+        case BytesProcessed:
+            counts->processed += bytes;
+            break;
+        case EntryFinished:
+            counts->finished++;
+            break;
+    }
+    return counts->cancel_after == 0 || counts->finished < counts->cancel_after;
+}
+
+bool complete(struct Counts* counts) {
+    return counts->entries == counts->started && counts->entries == counts->finished
+        && counts->bytes == counts->processed;
+}
+
+int main(void) {
+    MEU3_Error err = -1;
+    struct Counts counts = { 0 };
+    struct MEU3_PackOptions options = { NULL, 0, NULL, 0, count, &counts };
+    MEU3_PACKAGE* pack = meu3_package_dir_with("test_dir", &options, &err);
+    if(!pack || !complete(&counts) || counts.entries != 3)
+        return 1;
+
+    memset(&counts, 0, sizeof(counts));
+    bool res = meu3_write_package_with_progress("dump/progress", pack, count, &counts, &err);
+    if(!res || !complete(&counts))
+        return 1;
+
+    memset(&counts, 0, sizeof(counts));
+    res = meu3_unpack_package(pack, "dump/progress_unpacked", count, &counts, &err);
+    if(!res || !complete(&counts))
+        return 1;
+
+    // returning false from the callback cancels the operation
+    memset(&counts, 0, sizeof(counts));
+    counts.cancel_after = 1;
+    res = meu3_unpack_package(pack, "dump/progress_cancelled", count, &counts, &err);
+    if(res || err != Cancelled || counts.finished != 1)
+        return 1;
+
+    err = -1;
+    res = meu3_unpack_package(pack, "dump/progress_silent", NULL, NULL, &err);
+    if(!res)
+        return 1;
+    meu3_free_package(pack);
+    return 0;
+}
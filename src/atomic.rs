@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::err::WriteError;
+
+/// A temporary file next to the file it replaces once it is complete, so the destination is
+/// never left partially written. The temporary file is removed again if it is dropped before
+/// being committed
+pub(crate) struct AtomicFile {
+    file: fs::File,
+    temp: PathBuf,
+    dest: PathBuf,
+    backup: bool,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file for `dest`, `backup` keeps the file it replaces as
+    /// `<dest>.bak`
+    pub(crate) fn create(dest: PathBuf, backup: bool) -> Result<Self, WriteError> {
+        let incomplete = |source| WriteError::IncompleteWrite {
+            path: dest.clone(),
+            source,
+        };
+        let name = dest.file_name().ok_or_else(|| {
+            incomplete(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path does not name a file",
+            ))
+        })?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{:08x}.tmp", rand::random::<u32>()));
+        let temp = dest.with_file_name(temp_name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .map_err(incomplete)?;
+        Ok(Self {
+            file,
+            temp,
+            dest,
+            backup,
+            committed: false,
+        })
+    }
+    /// The canonical paths of the temporary file and the destination, which must not end up in
+    /// the package being written
+    pub(crate) fn own_files(&self) -> Result<Vec<PathBuf>, WriteError> {
+        let temp = fs::canonicalize(&self.temp).map_err(|e| self.incomplete(e))?;
+        let dest = temp.with_file_name(self.dest.file_name().unwrap_or_default());
+        Ok(vec![temp, dest])
+    }
+    /// Another handle to the temporary file, written to before [`AtomicFile::commit`]
+    pub(crate) fn handle(&self) -> io::Result<fs::File> {
+        self.file.try_clone()
+    }
+    pub(crate) fn write_all(&mut self, buf: &[u8]) -> Result<(), WriteError> {
+        self.file.write_all(buf).map_err(|e| self.incomplete(e))
+    }
+    /// Flushes the temporary file to disk and moves it over the destination
+    pub(crate) fn commit(mut self) -> Result<(), WriteError> {
+        self.file.sync_all().map_err(|e| self.incomplete(e))?;
+        if self.backup && self.dest.exists() {
+            backup(&self.dest).map_err(|e| self.incomplete(e))?;
+        }
+        fs::rename(&self.temp, &self.dest).map_err(|e| self.incomplete(e))?;
+        self.committed = true;
+        // the rename itself only survives a crash once the directory is flushed as well, which
+        // is not possible on every platform
+        #[cfg(unix)]
+        if let Some(parent) = self.dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+            let _ = fs::File::open(parent).and_then(|dir| dir.sync_all());
+        }
+        Ok(())
+    }
+    pub(crate) fn incomplete(&self, source: io::Error) -> WriteError {
+        WriteError::IncompleteWrite {
+            path: self.dest.clone(),
+            source,
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Keeps the current contents of `path` as `<path>.bak`, replacing an older backup
+fn backup(path: &Path) -> io::Result<()> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    match fs::remove_file(&backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    // linking keeps the destination in place until it is replaced, not every file system can
+    if fs::hard_link(path, &backup).is_err() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(())
+}
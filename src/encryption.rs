@@ -0,0 +1,199 @@
+use std::io::Read;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::err::{self, EncryptionError, LimitError};
+use crate::LoadLimits;
+
+/// Set in the encryption header field when the data of every entry is encrypted
+pub(crate) const ENTRIES_ENCRYPTED: u8 = 0b01;
+/// Set in the encryption header field when the data table is encrypted as well
+pub(crate) const TABLE_ENCRYPTED: u8 = 0b10;
+
+const KDF_RAW: u8 = 0x00;
+const KDF_ARGON2ID: u8 = 0x01;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The secret a package is encrypted with
+#[derive(Clone)]
+pub enum EncryptionKey {
+    /// A passphrase the encryption key is derived from with Argon2id
+    Passphrase(String),
+    /// A raw 256-bit key used as is
+    Raw([u8; 32]),
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionKey::Passphrase(_) => f.write_str("Passphrase(..)"),
+            EncryptionKey::Raw(_) => f.write_str("Raw(..)"),
+        }
+    }
+}
+
+/// Encryption applied when a package is written
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub key: EncryptionKey,
+    /// Also encrypt the data table, hiding the names and sizes of the entries
+    pub encrypt_table: bool,
+}
+
+/// How the cipher key is obtained from an [`EncryptionKey`], stored in the package header
+pub(crate) enum KeyDerivation {
+    Raw,
+    Argon2id {
+        salt: [u8; SALT_LEN],
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+impl KeyDerivation {
+    /// Creates the key derivation for a newly written package, passphrases get a fresh salt
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        match key {
+            EncryptionKey::Raw(_) => KeyDerivation::Raw,
+            EncryptionKey::Passphrase(_) => {
+                let mut salt = [0u8; SALT_LEN];
+                rand::fill(&mut salt);
+                KeyDerivation::Argon2id {
+                    salt,
+                    m_cost: Params::DEFAULT_M_COST,
+                    t_cost: Params::DEFAULT_T_COST,
+                    p_cost: Params::DEFAULT_P_COST,
+                }
+            }
+        }
+    }
+    pub(crate) fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            KeyDerivation::Raw => buf.push(KDF_RAW),
+            KeyDerivation::Argon2id {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                buf.push(KDF_ARGON2ID);
+                buf.extend_from_slice(salt);
+                buf.extend_from_slice(&m_cost.to_le_bytes());
+                buf.extend_from_slice(&t_cost.to_le_bytes());
+                buf.extend_from_slice(&p_cost.to_le_bytes());
+            }
+        }
+    }
+    /// Reads the key derivation stored at `offset` of the package header
+    pub(crate) fn read<R: Read>(reader: &mut R, offset: u64) -> Result<Self, err::ParseError> {
+        let mut kdf = [0u8; 1];
+        reader
+            .read_exact(&mut kdf)
+            .map_err(|_| err::ParseError::Encryption { offset })?;
+        match kdf[0] {
+            KDF_RAW => Ok(KeyDerivation::Raw),
+            KDF_ARGON2ID => {
+                let mut params = [0u8; SALT_LEN + 12];
+                reader
+                    .read_exact(&mut params)
+                    .map_err(|_| err::ParseError::Encryption { offset: offset + 1 })?;
+                let (salt, costs) = params.split_at(SALT_LEN);
+                let cost =
+                    |i: usize| u32::from_le_bytes(costs[i * 4..i * 4 + 4].try_into().unwrap());
+                Ok(KeyDerivation::Argon2id {
+                    salt: salt.try_into().unwrap(),
+                    m_cost: cost(0),
+                    t_cost: cost(1),
+                    p_cost: cost(2),
+                })
+            }
+            _ => Err(err::ParseError::Encryption { offset }),
+        }
+    }
+    /// Checks the costs read from the header of a package before any memory is spent on them
+    pub(crate) fn check_limits(&self, limits: &LoadLimits) -> Result<(), LimitError> {
+        match *self {
+            KeyDerivation::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                ..
+            } if m_cost > limits.max_kdf_memory
+                || t_cost > limits.max_kdf_passes
+                || p_cost > limits.max_kdf_lanes =>
+            {
+                Err(LimitError::KeyDerivation {
+                    memory: m_cost,
+                    passes: t_cost,
+                    lanes: p_cost,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+    pub(crate) fn derive(&self, key: &EncryptionKey) -> Result<Cipher, EncryptionError> {
+        let mut cipher_key = [0u8; 32];
+        match (self, key) {
+            (KeyDerivation::Raw, EncryptionKey::Raw(raw)) => cipher_key = *raw,
+            (
+                KeyDerivation::Argon2id {
+                    salt,
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                },
+                EncryptionKey::Passphrase(passphrase),
+            ) => {
+                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(cipher_key.len()))
+                    .map_err(|_| EncryptionError::KeyDerivation)?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut cipher_key)
+                    .map_err(|_| EncryptionError::KeyDerivation)?;
+            }
+            (KeyDerivation::Raw, EncryptionKey::Passphrase(_)) => {
+                return Err(EncryptionError::RawKeyRequired)
+            }
+            (KeyDerivation::Argon2id { .. }, EncryptionKey::Raw(_)) => {
+                return Err(EncryptionError::PassphraseRequired)
+            }
+        }
+        Ok(Cipher(ChaCha20Poly1305::new(Key::from_slice(&cipher_key))))
+    }
+}
+
+/// ChaCha20-Poly1305 with a random nonce prepended to every ciphertext
+pub(crate) struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    pub(crate) fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::fill(&mut nonce);
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
+            .map_err(|_| EncryptionError::Encryption)?;
+        let mut buf = nonce.to_vec();
+        buf.extend_from_slice(&ciphertext);
+        Ok(buf)
+    }
+    /// Returns `None` if the key is wrong or the data was tampered with
+    pub(crate) fn decrypt(&self, data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
@@ -0,0 +1,172 @@
+use std::borrow::Cow;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use crate::Compression;
+
+pub(crate) trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A `Read + Seek` stream over the data of a single entry of a package
+pub struct EntryReader<'a> {
+    inner: Box<dyn ReadSeek + 'a>,
+}
+
+impl<'a> EntryReader<'a> {
+    /// Streams data that is already decoded
+    pub(crate) fn from_memory(data: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self {
+            inner: Box::new(Cursor::new(data.into())),
+        }
+    }
+    /// Streams an entry whose stored bytes are opened by `stored`, decompressing them as they are
+    /// read. `compression` must be able to stream, see [`Compression::can_stream`]
+    pub(crate) fn decoding<R: ReadSeek + 'a>(
+        compression: Compression,
+        original_size: Option<u64>,
+        stored: impl Fn() -> R + 'a,
+    ) -> io::Result<Self> {
+        if compression == Compression::None {
+            return Ok(Self {
+                inner: Box::new(stored()),
+            });
+        }
+        let open = move || compression.decoder(stored());
+        Ok(Self {
+            inner: Box::new(Decoder {
+                decoder: open()?,
+                open: Box::new(open),
+                pos: 0,
+                len: original_size,
+            }),
+        })
+    }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for EntryReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Resolves a seek relative to the current position `pos` of a stream of length `len`
+fn seek_target(pos: u64, len: u64, seek: SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match seek {
+        SeekFrom::Start(n) => return Ok(n),
+        SeekFrom::End(offset) => (len, offset),
+        SeekFrom::Current(offset) => (pos, offset),
+    };
+    base.checked_add_signed(offset).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// The stored bytes of an entry, read straight from the shared reader of a package
+pub(crate) struct Window<'a> {
+    reader: &'a Mutex<dyn ReadSeek + 'a>,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> Window<'a> {
+    pub(crate) fn new(reader: &'a Mutex<dyn ReadSeek + 'a>, start: u64, len: u64) -> Self {
+        Self {
+            reader,
+            start,
+            len,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Window<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let n = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        // other streams may have moved the reader since the last read
+        reader.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = reader.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Window<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_target(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Decompresses an entry as it is read. Seeking forward decodes and discards the data in
+/// between, seeking backward starts decoding over from the beginning
+struct Decoder<'a> {
+    open: Box<dyn Fn() -> io::Result<Box<dyn Read + 'a>> + 'a>,
+    decoder: Box<dyn Read + 'a>,
+    pos: u64,
+    /// The decompressed size, if it is stored in the package or the end was already reached
+    len: Option<u64>,
+}
+
+impl Decoder<'_> {
+    /// Decodes up to `n` bytes without keeping them, returns how many there were
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let skipped = io::copy(&mut self.decoder.by_ref().take(n), &mut io::sink())?;
+        self.pos += skipped;
+        if skipped < n {
+            self.len = Some(self.pos);
+        }
+        Ok(skipped)
+    }
+}
+
+impl Read for Decoder<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.len.is_some_and(|len| self.pos >= len) {
+            return Ok(0);
+        }
+        let n = self.decoder.read(buf)?;
+        self.pos += n as u64;
+        if n == 0 && !buf.is_empty() {
+            self.len = Some(self.pos);
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for Decoder<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = match (pos, self.len) {
+            (_, Some(len)) => len,
+            (SeekFrom::End(_), None) => {
+                // the size is only known after decoding everything
+                self.skip(u64::MAX)?;
+                self.pos
+            }
+            (_, None) => u64::MAX,
+        };
+        let target = seek_target(self.pos, len, pos)?;
+        if target < self.pos {
+            self.decoder = (self.open)()?;
+            self.pos = 0;
+        }
+        self.skip(target - self.pos)?;
+        // seeking past the end is allowed, reads there return nothing
+        self.pos = target;
+        Ok(target)
+    }
+}
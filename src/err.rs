@@ -1,15 +1,13 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
-pub enum UnpackError {
-    #[error("file is not a valid .m3pgk file")]
-    InvalidFile,
+use std::path::{Path, PathBuf};
 
-    #[error("file is of invalid version")]
-    InvalidVersion,
+use crate::PackageVersion;
 
-    #[error("unknown compression format")]
-    InvalidCompression,
+#[derive(Error, Debug)]
+pub enum UnpackError {
+    #[error("file is not a valid .m3pkg file, {reason} at offset {offset}")]
+    InvalidFile { offset: u64, reason: &'static str },
 
     #[error(transparent)]
     ParseError(#[from] ParseError),
@@ -17,37 +15,157 @@ pub enum UnpackError {
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
-    #[error("unsupported file format")]
-    FileExtension,
+    #[error("failed to access `{}`", path.display())]
+    FileError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("entry name at offset {offset} of the data table is not valid UTF-8")]
+    StringError {
+        offset: u64,
+        source: std::string::FromUtf8Error,
+    },
+
+    #[error("target of symlink `{name}` is not valid UTF-8")]
+    LinkTargetError {
+        name: String,
+        source: std::string::FromUtf8Error,
+    },
+
+    #[error("package is encrypted, a key is required to load it")]
+    KeyRequired,
+
+    #[error("failed to decrypt entry `{name}`, the key is wrong or the package was tampered with")]
+    DecryptionError { name: String },
+
+    #[error("failed to decrypt the data table, the key is wrong or the package was tampered with")]
+    TableDecryptionError,
+
+    #[error(transparent)]
+    EncryptionError(#[from] EncryptionError),
+
+    #[error("package signature is missing or was not made by a trusted key")]
+    BadSignature,
 
-    #[error("error when reading file name string")]
-    StringError(#[from] std::string::FromUtf8Error),
+    #[error("checksum of entry `{name}` does not match its data")]
+    ChecksumMismatch { name: String },
+
+    #[error("failed to decompress entry `{name}`")]
+    DecompressionError {
+        name: String,
+        source: std::io::Error,
+    },
 
     #[error(transparent)]
     UnsupportedFormat(#[from] UnsupportedError),
+
+    #[error("entry `{name}` would end up outside of the destination directory")]
+    UnsafePath { name: String },
+
+    #[error(transparent)]
+    LimitExceeded(#[from] LimitError),
+
+    #[error("unpacking was cancelled")]
+    Cancelled,
+}
+
+impl UnpackError {
+    /// Turns an I/O error on the file at `path` into an error naming it
+    pub(crate) fn file(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::FileError {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum UnsupportedError {
-    #[error("unsupported file version")]
-    Version,
+    #[error("unsupported file version {version}")]
+    Version { version: PackageVersion },
 
     #[error("unsupported file compression")]
     Compression,
+
+    #[error("unsupported file encryption")]
+    Encryption,
+
+    #[error("unsupported entry type")]
+    EntryKind,
 }
 
+/// Offsets count from the start of the package file, inside of an encrypted data table they count
+/// from the start of the decrypted table
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("failed to parse version")]
-    Version,
-    #[error("failed to parse compression format")]
-    Compression,
-    #[error("failed to parse extension format")]
-    Extension,
-    #[error("failed to parse index data")]
-    Index,
-    #[error("failed to parse size data")]
-    Size,
+    #[error("a package version takes 4 bytes, not {len}")]
+    Version { len: usize },
+    #[error("unknown compression format {codec:02x?}")]
+    Compression { codec: Vec<u8> },
+    #[error("failed to parse encryption header at offset {offset}")]
+    Encryption { offset: u64 },
+    #[error("unknown entry metadata marker {marker:#04x}")]
+    Metadata { marker: u8 },
+    #[error("unknown entry type {kind:#04x}")]
+    EntryKind { kind: u8 },
+    #[error("invalid data table entry `{name}` at offset {offset}")]
+    InvalidEntry {
+        name: String,
+        offset: u64,
+        source: Box<ParseError>,
+    },
+    #[error("data table ends at offset {offset} before its last entry is complete")]
+    Truncated { offset: u64 },
+    #[error(
+        "data of entry `{name}`, {size} bytes at offset {offset}, lies outside of the package \
+         data ending at offset {data_end}"
+    )]
+    OutOfBounds {
+        name: String,
+        offset: u64,
+        size: u64,
+        data_end: u64,
+    },
+    #[error("data of entries `{first}` and `{second}` overlaps at offset {offset}")]
+    OverlappingEntries {
+        first: String,
+        second: String,
+        offset: u64,
+    },
+    #[error("data table lists entry `{name}` more than once, again at offset {offset}")]
+    DuplicateName { name: String, offset: u64 },
+}
+
+#[derive(Error, Debug)]
+pub enum LimitError {
+    #[error("package has more than {0} entries")]
+    Entries(usize),
+    #[error("package has an entry name longer than {0} bytes")]
+    NameLength(usize),
+    #[error("package data decompresses to more than {0} bytes")]
+    TotalSize(u64),
+    #[error(
+        "package key derivation asks for {memory} KiB, {passes} passes and {lanes} lanes, more \
+         than the limits allow"
+    )]
+    KeyDerivation {
+        memory: u32,
+        passes: u32,
+        lanes: u32,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("failed to derive a key from the passphrase")]
+    KeyDerivation,
+    #[error("failed to encrypt package data")]
+    Encryption,
+    #[error("package is encrypted with a raw key, not a passphrase")]
+    RawKeyRequired,
+    #[error("package is encrypted with a passphrase, not a raw key")]
+    PassphraseRequired,
 }
 
 #[derive(Error, Debug)]
@@ -55,8 +173,66 @@ pub enum PackingError {
     #[error(transparent)]
     FileReadingError(#[from] std::path::StripPrefixError),
 
+    #[error("failed to read `{}`", path.display())]
+    IoError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("packing was cancelled")]
+    Cancelled,
+}
+
+impl PackingError {
+    /// Turns an I/O error on the file at `path` into an error naming it
+    pub(crate) fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::IoError {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WriteError {
+    #[error("entry `{name}` does not fit into the data table of package version {version}")]
+    EntryTooLarge {
+        name: String,
+        version: PackageVersion,
+    },
+
+    #[error("the package already contains an entry named `{name}`")]
+    DuplicateEntry { name: String },
+
+    #[error("failed to write `{}`, the previous file was left in place", path.display())]
+    IncompleteWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to read `{}`", path.display())]
+    ReadError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    InvalidName(#[from] InsertError),
+
+    #[error(transparent)]
+    PackingError(#[from] PackingError),
+
+    #[error(transparent)]
+    UnsupportedFormat(#[from] UnsupportedError),
+
+    #[error(transparent)]
+    EncryptionError(#[from] EncryptionError),
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+
+    #[error("writing the package was cancelled")]
+    Cancelled,
 }
 
 #[derive(Error, Debug)]
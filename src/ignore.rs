@@ -0,0 +1,296 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::err::PackingError;
+use crate::PackOptions;
+
+/// Name of the files listing what to leave out when a directory is packaged, in the syntax of
+/// `.gitignore` files
+pub(crate) const IGNORE_FILE: &str = ".m3ignore";
+
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`, which stays inside of a single directory
+    Star,
+    /// `**/`, any number of whole directories
+    AnyDirs,
+    /// A trailing `**`, everything inside of a directory
+    Everything,
+    /// `[a-z]`, or `[!a-z]` when negated
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(expected) => c == *expected,
+            Token::Class { negated, ranges } => {
+                c != '/' && ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+            _ => c != '/',
+        }
+    }
+}
+
+/// A single line of a `.m3ignore` file, or an include or exclude pattern
+struct Pattern {
+    tokens: Vec<Token>,
+    negated: bool,
+    /// The pattern ended with a `/` and only matches directories
+    dir_only: bool,
+    /// The pattern contains a `/` and matches paths from the directory it applies to, instead of
+    /// names at any depth
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Returns `None` for blank lines and comments
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        // trailing spaces are dropped unless the last one is escaped
+        let trimmed = line.trim_end_matches(' ');
+        let mut line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            &line[..trimmed.len() + 1]
+        } else {
+            trimmed
+        };
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let negated = line.starts_with('!');
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Self {
+            tokens: tokenize(line),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = if self.anchored {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        let text: Vec<char> = path.chars().collect();
+        glob_match(&self.tokens, &text)
+    }
+}
+
+fn tokenize(glob: &str) -> Vec<Token> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let segment_start = i == 0 || chars[i - 1] == '/';
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 2;
+            }
+            '*' if chars.get(i + 1) == Some(&'*') && segment_start => match chars.get(i + 2) {
+                Some('/') => {
+                    tokens.push(Token::AnyDirs);
+                    i += 3;
+                }
+                None => {
+                    tokens.push(Token::Everything);
+                    i += 2;
+                }
+                // `**` followed by more of a name is a plain `*`
+                Some(_) => {
+                    tokens.push(Token::Star);
+                    i += 2;
+                }
+            },
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+                while chars.get(i) == Some(&'*') {
+                    i += 1;
+                }
+            }
+            '?' => {
+                tokens.push(Token::Any);
+                i += 1;
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((token, len)) => {
+                    tokens.push(token);
+                    i += len + 1;
+                }
+                None => {
+                    tokens.push(Token::Char('['));
+                    i += 1;
+                }
+            },
+            c => {
+                tokens.push(Token::Char(c));
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses a character class following its `[`, returning it along with the number of
+/// characters it took up to and including the closing `]`
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = vec![];
+    let class_start = i;
+    loop {
+        let c = *chars.get(i)?;
+        // a `]` right at the start is part of the class
+        if c == ']' && i > class_start {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|c| *c != ']') {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+}
+
+/// Matches in time proportional to the length of the pattern times the length of the text, by
+/// keeping track of every position of the text the tokens so far can end at instead of
+/// backtracking on each `*`
+fn glob_match(tokens: &[Token], text: &[char]) -> bool {
+    let mut reached = vec![false; text.len() + 1];
+    reached[0] = true;
+    for token in tokens {
+        let mut next = vec![false; text.len() + 1];
+        match token {
+            Token::Star => {
+                for i in 0..=text.len() {
+                    next[i] = reached[i] || (i > 0 && next[i - 1] && text[i - 1] != '/');
+                }
+            }
+            Token::AnyDirs => {
+                let mut seen = false;
+                for i in 0..=text.len() {
+                    next[i] = reached[i] || (seen && text[i - 1] == '/');
+                    seen |= reached[i];
+                }
+            }
+            Token::Everything => {
+                let mut seen = false;
+                for i in 0..=text.len() {
+                    seen |= reached[i];
+                    next[i] = seen;
+                }
+            }
+            token => {
+                for i in 0..text.len() {
+                    next[i + 1] = reached[i] && token.matches(text[i]);
+                }
+            }
+        }
+        if !next.contains(&true) {
+            return false;
+        }
+        reached = next;
+    }
+    reached[text.len()]
+}
+
+/// Whether the last of `patterns` matching `path` includes it, `None` if none match
+fn last_match(patterns: &[Pattern], path: &str, is_dir: bool) -> Option<bool> {
+    patterns
+        .iter()
+        .rev()
+        .find(|p| p.matches(path, is_dir))
+        .map(|p| !p.negated)
+}
+
+/// Decides which of the files found while walking a directory are packaged
+pub(crate) struct Filter {
+    /// The patterns of the `.m3ignore` files of the directories being walked, outermost first,
+    /// along with the path of the directory they apply to
+    ignores: Vec<(String, Vec<Pattern>)>,
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+}
+
+impl Filter {
+    pub(crate) fn new(options: &PackOptions) -> Self {
+        let parse =
+            |patterns: &[String]| patterns.iter().filter_map(|p| Pattern::parse(p)).collect();
+        Self {
+            ignores: vec![],
+            exclude: parse(&options.exclude),
+            include: parse(&options.include),
+        }
+    }
+    /// Reads the `.m3ignore` file of a directory about to be walked, `rel` being its path inside
+    /// of the packaged directory
+    pub(crate) fn enter(&mut self, dir: &Path, rel: &str) -> Result<(), PackingError> {
+        let path = dir.join(IGNORE_FILE);
+        let patterns = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter_map(Pattern::parse).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(PackingError::io(&path)(e)),
+        };
+        self.ignores.push((rel.to_string(), patterns));
+        Ok(())
+    }
+    /// Drops the `.m3ignore` patterns of the directory that was entered last
+    pub(crate) fn leave(&mut self) {
+        self.ignores.pop();
+    }
+    /// Whether a file or directory is left out by the `.m3ignore` files or exclude patterns,
+    /// later and deeper patterns taking precedence
+    pub(crate) fn is_excluded(&self, rel: &str, is_dir: bool) -> bool {
+        let mut excluded = false;
+        for (base, patterns) in &self.ignores {
+            let path = match base.as_str() {
+                "" => Some(rel),
+                base => rel.strip_prefix(base).and_then(|p| p.strip_prefix('/')),
+            };
+            if let Some(matched) = path.and_then(|path| last_match(patterns, path, is_dir)) {
+                excluded = matched;
+            }
+        }
+        last_match(&self.exclude, rel, is_dir).unwrap_or(excluded)
+    }
+    /// Whether a file or directory is matched by the include patterns, itself or through one of
+    /// the directories it is in. Everything is included without include patterns
+    pub(crate) fn is_included(&self, rel: &str, is_dir: bool) -> bool {
+        if self.include.is_empty() {
+            return true;
+        }
+        let ancestors = rel.match_indices('/').rev().map(|(i, _)| (&rel[..i], true));
+        std::iter::once((rel, is_dir))
+            .chain(ancestors)
+            .find_map(|(path, is_dir)| last_match(&self.include, path, is_dir))
+            .unwrap_or(false)
+    }
+    pub(crate) fn accepts(&self, rel: &str, is_dir: bool) -> bool {
+        !self.is_excluded(rel, is_dir) && self.is_included(rel, is_dir)
+    }
+}
@@ -1,228 +1,760 @@
-use crate::err::UnpackError;
-use bytes::Buf;
 #[cfg(target_os = "windows")]
 use path_slash::{PathBufExt, PathExt};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, DirBuilder};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
 
-mod err;
+mod atomic;
+mod encryption;
+mod entry;
+pub mod err;
+mod ignore;
+mod mapped;
+mod options;
 mod package;
+mod parallel;
+mod parse;
+mod progress;
+mod reader;
+mod signature;
 #[cfg(test)]
 mod tests;
+mod writer;
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+pub use encryption::{Encryption, EncryptionKey};
+pub use entry::EntryReader;
+pub use mapped::MappedPackage;
+pub use options::{
+    LoadLimits, LoadOptions, PackOptions, SymlinkPolicy, UnpackOptions, WriteOptions,
+};
 use package::*;
-pub use package::{Compression, Package, PackageVersion};
+pub use package::{Compression, EntryInfo, EntryKind, EntryMetadata, Package, PackageVersion};
+use parse::{decode_entry, read_header, read_package_table};
+pub use progress::{CancellationToken, NoProgress, ProgressSink};
+pub use reader::PackageReader;
+pub use writer::PackageWriter;
 
 const FILE_HEADER: [u8; 4] = [0xFF, 0x69, 0xFF, 0x69];
-const VERSION_0_0_0_1: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
-const NO_COMPRESSION: [u8; 2] = [0x00, 0x00];
+/// The version new packages are created with
+const CURRENT_VERSION: (u8, u8, u8, u8) = (0, 0, 7, 0);
 
-fn collect_files(dir: &std::path::Path) -> std::io::Result<Vec<(fs::DirEntry, PathBuf)>> {
+/// A file system entry found while walking a directory
+enum Found {
+    File(PathBuf),
+    Symlink(PathBuf),
+    Directory(PathBuf),
+}
+
+/// Walks `dir` for the entries to package, leaving out the files whose canonical paths are in
+/// `skip`
+fn collect_files(
+    dir: &Path,
+    options: &PackOptions,
+    skip: Vec<PathBuf>,
+) -> Result<Vec<Found>, err::PackingError> {
     let mut ret = vec![];
-    for entry in fs::read_dir(dir)? {
-        if let Ok(entry) = entry {
+    let mut filter = ignore::Filter::new(options);
+    filter.enter(dir, "")?;
+    let mut walk = Walk {
+        symlinks: options.symlinks,
+        filter,
+        skip,
+        visited: vec![fs::canonicalize(dir).map_err(err::PackingError::io(dir))?],
+    };
+    walk.collect_into(dir, "", &mut ret)?;
+    // in the order of their names like `write_package` lays entries out, so the same tree always
+    // gives the same package whichever way it is written
+    ret.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(ret.into_iter().map(|(_, found)| found).collect())
+}
+
+/// The state of a directory walk
+struct Walk {
+    symlinks: SymlinkPolicy,
+    filter: ignore::Filter,
+    /// The canonical paths of files left out, like the package being written
+    skip: Vec<PathBuf>,
+    /// The canonical paths of the directories being walked, so following a link to one of them
+    /// does not loop forever
+    visited: Vec<PathBuf>,
+}
+
+impl Walk {
+    /// Collects the contents of `dir` along with their entry names, `rel_dir` being its path
+    /// inside of the walked directory
+    fn collect_into(
+        &mut self,
+        dir: &Path,
+        rel_dir: &str,
+        ret: &mut Vec<(String, Found)>,
+    ) -> Result<(), err::PackingError> {
+        let entries = fs::read_dir(dir).map_err(err::PackingError::io(dir))?;
+        for entry in entries.flatten() {
             let path = entry.path();
+            let name = entry.file_name();
+            let rel = match rel_dir {
+                "" => name.to_string_lossy().to_string(),
+                rel_dir => format!("{rel_dir}/{}", name.to_string_lossy()),
+            };
+            let file_type = entry.file_type().map_err(err::PackingError::io(&path))?;
+            if file_type.is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Follow => {}
+                    SymlinkPolicy::Link => {
+                        if self.filter.accepts(&rel, false) {
+                            ret.push((rel, Found::Symlink(path)));
+                        }
+                        continue;
+                    }
+                    SymlinkPolicy::Skip => continue,
+                }
+            }
             if path.is_dir() {
-                let mut inner_files = collect_files(&path)?;
-                ret.append(&mut inner_files);
+                let canonical = fs::canonicalize(&path).map_err(err::PackingError::io(&path))?;
+                if self.filter.is_excluded(&rel, true) || self.visited.contains(&canonical) {
+                    continue;
+                }
+                self.visited.push(canonical);
+                let found_at = ret.len();
+                ret.push((rel.clone(), Found::Directory(path.clone())));
+                self.filter.enter(&path, &rel)?;
+                self.collect_into(&path, &rel, ret)?;
+                self.filter.leave();
+                self.visited.pop();
+                // with include patterns directories are only kept for the files they hold
+                if ret.len() == found_at + 1 && !self.filter.is_included(&rel, true) {
+                    ret.pop();
+                }
+            } else if path.is_file() && self.filter.accepts(&rel, false) && !self.skips(&path) {
+                ret.push((rel, Found::File(path)));
             }
-            ret.push((entry, path));
+            // dangling links and special files are left out
         }
+        Ok(())
+    }
+    fn skips(&self, path: &Path) -> bool {
+        // only files sharing a name with a skipped one are worth canonicalizing
+        self.skip
+            .iter()
+            .any(|skip| skip.file_name() == path.file_name())
+            && fs::canonicalize(path).is_ok_and(|path| self.skip.contains(&path))
     }
-    Ok(ret)
+}
+
+/// The size of the files found while walking a directory
+fn files_size(found: &[Found]) -> Result<u64, err::PackingError> {
+    found
+        .iter()
+        .map(|found| match found {
+            Found::File(path) => Ok(fs::metadata(path)
+                .map_err(err::PackingError::io(path))?
+                .len()),
+            _ => Ok(0),
+        })
+        .sum()
+}
+
+/// The target of a symlink as it is stored in a package, with forward slashes
+fn link_target(path: &Path) -> Result<String, err::PackingError> {
+    let target = fs::read_link(path).map_err(err::PackingError::io(path))?;
+
+    #[cfg(target_os = "windows")]
+    let target: PathBuf = target
+        .to_slash()
+        .expect("slash replacement in link target failed, link target must contain non-unicode characters")
+        .to_string()
+        .into();
+
+    Ok(target.to_string_lossy().to_string())
+}
+
+/// The name a file inside of `dir_path` is stored as, its relative path with forward slashes
+fn entry_name(dir_path: &Path, full_path: &Path) -> Result<String, err::PackingError> {
+    let rel_path = full_path
+        .strip_prefix(dir_path)
+        .map_err(err::PackingError::FileReadingError)?;
+
+    #[cfg(target_os = "windows")]
+    let rel_path: PathBuf = rel_path
+        .to_slash()
+        .expect(
+            "slash replacement in file path failed, file path must contain non-unicode characters",
+        )
+        .to_string()
+        .into();
+
+    Ok(rel_path.to_string_lossy().to_string())
 }
 
 pub fn package_dir(dir_path: PathBuf) -> Result<Package, err::PackingError> {
-    let dir_path = std::fs::canonicalize(dir_path)?;
-    let files = collect_files(&dir_path)?
-        .into_iter()
-        .filter_map(|(f, p)| {
-            if p.is_file() {
-                return Some((f, p));
+    package_dir_with(dir_path, &PackOptions::default())
+}
+
+/// Packages a directory as `options` ask for. Files and directories listed in the `.m3ignore`
+/// files of the directory and its subdirectories are left out, the files use the syntax of
+/// `.gitignore` files
+pub fn package_dir_with(
+    dir_path: PathBuf,
+    options: &PackOptions,
+) -> Result<Package, err::PackingError> {
+    package_dir_with_progress(dir_path, options, &mut NoProgress)
+}
+
+/// Packages a directory like [`package_dir_with`], reporting every file read to `progress`
+pub fn package_dir_with_progress(
+    dir_path: PathBuf,
+    options: &PackOptions,
+    progress: &mut dyn ProgressSink,
+) -> Result<Package, err::PackingError> {
+    enum Loaded {
+        File(String, Vec<u8>, EntryMetadata),
+        Symlink(String, String),
+        Directory(String, EntryMetadata),
+    }
+
+    let dir_path = fs::canonicalize(&dir_path).map_err(err::PackingError::io(&dir_path))?;
+    let mut files = vec![];
+    let mut links = HashMap::new();
+    let mut dirs = vec![];
+    let found = collect_files(&dir_path, options, vec![])?;
+    progress.total(found.len(), files_size(&found)?);
+    // files are read on several threads, but kept in the order they were found in
+    parallel::map_ordered(
+        found,
+        options.threads,
+        |found| -> Result<Loaded, err::PackingError> {
+            if options.cancel.is_cancelled() {
+                return Err(err::PackingError::Cancelled);
             }
-            None
-        })
-        .map(|(_f, p)| {
-            let full_path = p.canonicalize()?;
-            let buf = std::fs::read(&full_path)?;
-
-            let rel_path = full_path
-                .strip_prefix(&dir_path)
-                .map_err(err::PackingError::FileReadingError)?;
-
-            #[cfg(target_os = "windows")]
-            let rel_path: PathBuf = rel_path
-                .to_slash()
-                .expect("slash replacement in file path failed, file path must contain non-unicode characters")
-                .to_string()
-                .into();
-
-            Ok(FileInfo::new(
-                rel_path.to_path_buf(),
-                buf,
-            ))
-        })
-        .collect::<Result<Vec<_>, err::PackingError>>()?;
-    Ok(Package::from_file_info(
+            Ok(match found {
+                Found::File(path) => {
+                    let buf = fs::read(&path).map_err(err::PackingError::io(&path))?;
+                    let metadata = fs::metadata(&path).map_err(err::PackingError::io(&path))?;
+                    let metadata = options.entry_metadata(&metadata);
+                    Loaded::File(entry_name(&dir_path, &path)?, buf, metadata)
+                }
+                Found::Symlink(path) => {
+                    Loaded::Symlink(entry_name(&dir_path, &path)?, link_target(&path)?)
+                }
+                Found::Directory(path) => {
+                    let metadata = fs::metadata(&path).map_err(err::PackingError::io(&path))?;
+                    let metadata = options.entry_metadata(&metadata);
+                    Loaded::Directory(entry_name(&dir_path, &path)?, metadata)
+                }
+            })
+        },
+        |loaded| {
+            match loaded {
+                Loaded::File(name, buf, metadata) => {
+                    progress::report_entry(progress, &name, buf.len() as u64);
+                    files.push(FileInfo::new(name.into(), buf).with_metadata(metadata));
+                }
+                Loaded::Symlink(name, target) => {
+                    progress::report_entry(progress, &name, 0);
+                    links.insert(name, target);
+                }
+                Loaded::Directory(name, metadata) => {
+                    progress::report_entry(progress, &name, 0);
+                    dirs.push((name, metadata));
+                }
+            }
+            Ok(())
+        },
+    )?;
+    let mut package = Package::from_file_info(
         files,
-        PackageVersion::from((0, 0, 0, 2)),
+        PackageVersion::from(CURRENT_VERSION),
         Compression::None,
-    ))
+    );
+    package.links = links;
+    for (name, metadata) in dirs {
+        package.metadata.insert(name.clone(), metadata);
+        package.dirs.insert(name);
+    }
+    Ok(package)
 }
 
-pub fn write_package(mut path: PathBuf, package: &mut Package) -> std::io::Result<()> {
-    let mut buf: Vec<u8> = vec![];
+pub fn write_package(path: PathBuf, package: &mut Package) -> Result<(), err::WriteError> {
+    write_package_with(path, package, &WriteOptions::default())
+}
 
-    //header
-    buf.write_all(&FILE_HEADER)?;
-    let ver: [u8; 4] = package.version.into();
-    buf.write_all(&ver)?;
-    let comp: [u8; 2] = package.compression.into();
-    buf.write_all(&comp)?;
+/// Writes the package to a temporary file next to `path` first, which only replaces an existing
+/// package once it was written completely
+pub fn write_package_with(
+    path: PathBuf,
+    package: &mut Package,
+    options: &WriteOptions,
+) -> Result<(), err::WriteError> {
+    write_package_with_progress(path, package, options, &mut NoProgress)
+}
 
-    let mut package_data = vec![];
+/// Writes the package like [`write_package_with`], reporting every entry encoded to `progress`
+pub fn write_package_with_progress(
+    mut path: PathBuf,
+    package: &mut Package,
+    options: &WriteOptions,
+    progress: &mut dyn ProgressSink,
+) -> Result<(), err::WriteError> {
+    let buf = serialize_package(package, options, progress)?;
+    path.set_extension("m3pkg");
+    let mut file = atomic::AtomicFile::create(path, options.backup)?;
+    file.write_all(&buf[..])?;
+    file.commit()
+}
 
-    for (name, data) in &package.names {
-        buf.write_all(name.as_bytes())?;
-        buf.write_all(&[0x00])?;
-        let mut dbuf = [0u8; 8];
-        let index = package_data.len();
-        let idx = index.to_le_bytes();
-        dbuf[..3].copy_from_slice(&idx[..3]);
-        let sz = (data.len() as u32).to_le_bytes();
-        dbuf[4..7].copy_from_slice(&sz[..3]);
-        buf.write_all(&dbuf[..])?;
-        package_data.write_all(data.as_slice())?;
-    }
-    buf.write_all(&[0x0])?;
-
-    buf.write_all(&package_data[..])?;
+/// Writes the package like [`write_package`] and appends a signature trailer covering the
+/// whole file, which can be checked with [`load_package_verified`]
+pub fn write_package_signed(
+    mut path: PathBuf,
+    package: &mut Package,
+    key: &SigningKey,
+) -> Result<(), err::WriteError> {
+    let mut buf = serialize_package(package, &WriteOptions::default(), &mut NoProgress)?;
+    signature::sign(&mut buf, key);
     path.set_extension("m3pkg");
-    let mut file = fs::File::create(path)?;
+    let mut file = atomic::AtomicFile::create(path, false)?;
+    file.write_all(&buf[..])?;
+    file.commit()
+}
 
-    file.write_all(&buf[..])
+/// Signs an already written package file, replacing its previous signature if it had one
+pub fn sign_package(path: PathBuf, key: &SigningKey) -> Result<(), err::WriteError> {
+    let mut buf = fs::read(&path).map_err(|source| err::WriteError::ReadError {
+        path: path.clone(),
+        source,
+    })?;
+    buf.truncate(signature::unsigned_len(&buf, &key.verifying_key()));
+    signature::sign(&mut buf, key);
+    let mut file = atomic::AtomicFile::create(path, false)?;
+    file.write_all(&buf[..])?;
+    file.commit()
 }
 
-pub fn load_package(path_to_dir: PathBuf) -> Result<Package, err::UnpackError> {
-    let file = fs::read(path_to_dir)?;
-    let file_len = file.len();
-    let mut bytes = bytes::Bytes::from(file);
-
-    if file_len < FILE_HEADER.len() + VERSION_0_0_0_1.len() + NO_COMPRESSION.len() + 1 {
-        return Err(UnpackError::InvalidFile);
-    }
-
-    let mut remain = bytes.split_off(4);
-    if bytes.as_ref() != FILE_HEADER {
-        return Err(UnpackError::InvalidFile);
-    }
-    // read version
-    bytes = remain;
-    remain = bytes.split_off(4);
-    let version = PackageVersion::try_from(bytes.as_ref())?;
-
-    // read compression type
-    bytes = remain;
-    remain = bytes.split_off(2);
-    let compression = Compression::try_from(&bytes[..2])?;
-
-    use err::UnsupportedError;
-    match (version.ver, compression) {
-        ((0, 0, 0, 1..), Compression::None) => {
-            let (map, bytes) = read_data_table(&mut remain)?;
-            let data: Vec<u8> = bytes.collect::<Result<_, _>>()?;
-
-            let map = map
-                .into_iter()
-                .map(|(k, v)| {
-                    let d = data[v.index as usize..(v.index + v.size) as usize].to_owned();
-                    (k, d)
-                })
-                .collect::<HashMap<String, Vec<u8>>>();
-
-            Ok(Package {
-                names: map,
+/// Builds the package file, encoding the entries on as many threads as `options` ask for
+pub(crate) fn serialize_package(
+    package: &mut Package,
+    options: &WriteOptions,
+    progress: &mut dyn ProgressSink,
+) -> Result<Vec<u8>, err::WriteError> {
+    let version = package.version;
+    let (header, cipher) =
+        writer::new_header(version, package.compression, package.encryption.as_ref())?;
+    let mut buf = header.to_bytes();
+
+    // the table is kept apart from the header so it can be encrypted as a whole
+    let mut table: Vec<u8> = vec![];
+    let mut package_data = vec![];
+    let mut entries = HashMap::new();
+
+    let files = package
+        .names
+        .iter()
+        .map(|(name, data)| (name, data.as_slice(), EntryKind::File));
+    let links = package
+        .links
+        .iter()
+        .map(|(name, target)| (name, target.as_bytes(), EntryKind::Symlink));
+    // directories are implied by the names of their files in versions without entry types
+    let dirs = package
+        .dirs
+        .iter()
+        .filter(|_| version.has_entry_kinds())
+        .map(|name| (name, &[][..], EntryKind::Directory));
+    // written in order of their names, so the same package always gives the same file
+    let mut all: Vec<_> = files.chain(links).chain(dirs).collect();
+    all.sort_by_key(|(name, _, _)| *name);
+    let size = all.iter().map(|(_, data, _)| data.len() as u64).sum();
+    progress.total(all.len(), size);
+    let (compression, level) = (package.compression, package.compression_level);
+    // entries are encoded on several threads, but laid out in order
+    parallel::map_ordered(
+        all,
+        options.threads,
+        |(name, data, kind)| -> Result<_, err::WriteError> {
+            if options.cancel.is_cancelled() {
+                return Err(err::WriteError::Cancelled);
+            }
+            let (stored, info) = writer::encode_entry(
+                name,
+                data,
                 version,
                 compression,
-            })
-        }
-        (_, Compression::None) => Err(UnpackError::UnsupportedFormat(UnsupportedError::Version)),
+                level,
+                cipher.as_ref(),
+                kind,
+            )?;
+            Ok((name, stored, info, kind))
+        },
+        |(name, stored, info, kind)| {
+            let index = package_data.len() as u64;
+            let metadata = package.metadata(name);
+            writer::write_table_entry(&mut table, name, version, index, &info, metadata, kind)?;
+            package_data.write_all(stored.as_slice())?;
+            progress::report_entry(progress, name, info.original_size);
+            entries.insert(name.clone(), info);
+            Ok(())
+        },
+    )?;
+    let table = writer::seal_table(table, &header, cipher.as_ref())?;
+
+    if version.has_trailing_table() {
+        let table_offset = package_data.len() as u64;
+        buf.write_all(&package_data[..])?;
+        buf.write_all(&table)?;
+        buf.write_all(&writer::footer(table_offset))?;
+    } else {
+        buf.write_all(&table)?;
+        buf.write_all(&package_data[..])?;
     }
+    package.entries = entries;
+    Ok(buf)
 }
 
-#[derive(PartialEq)]
-enum ParseState {
-    String,
-    Index,
-    Size,
+pub fn load_package(path_to_dir: PathBuf) -> Result<Package, err::UnpackError> {
+    load_package_with(path_to_dir, &LoadOptions::default())
+}
+
+pub fn load_package_with(
+    path_to_dir: PathBuf,
+    options: &LoadOptions,
+) -> Result<Package, err::UnpackError> {
+    let file = fs::read(&path_to_dir).map_err(err::UnpackError::file(&path_to_dir))?;
+    parse_package(&file, options)
+}
+
+/// Loads a package from the bytes of a package file, such as one that was received over the
+/// network
+pub fn load_package_from_bytes(bytes: &[u8]) -> Result<Package, err::UnpackError> {
+    parse_package(bytes, &LoadOptions::default())
+}
+
+/// Loads and decrypts a package encrypted with `key`
+pub fn load_package_encrypted(
+    path_to_dir: PathBuf,
+    key: &EncryptionKey,
+) -> Result<Package, err::UnpackError> {
+    let options = LoadOptions {
+        key: Some(key.clone()),
+        ..Default::default()
+    };
+    load_package_with(path_to_dir, &options)
 }
 
-fn read_data_table(
-    bytes: &mut bytes::Bytes,
-) -> Result<
-    (
-        HashMap<String, DataInfo>,
-        std::io::Bytes<bytes::buf::Reader<&mut bytes::Bytes>>,
-    ),
-    err::UnpackError,
-> {
-    let mut map = HashMap::new();
-    let reader = bytes.reader();
-    let mut bytes = reader.bytes();
+/// Loads a package only if it carries a valid signature made by one of the `keys`
+pub fn load_package_verified(
+    path_to_dir: PathBuf,
+    keys: &[VerifyingKey],
+) -> Result<Package, err::UnpackError> {
+    load_package_verified_with(path_to_dir, keys, &LoadOptions::default())
+}
 
-    let mut state = ParseState::String;
+/// Loads a package like [`load_package_verified`] as `options` ask for, decrypting encrypted
+/// packages with their key once the signature is checked
+pub fn load_package_verified_with(
+    path_to_dir: PathBuf,
+    keys: &[VerifyingKey],
+    options: &LoadOptions,
+) -> Result<Package, err::UnpackError> {
+    let file = fs::read(&path_to_dir).map_err(err::UnpackError::file(&path_to_dir))?;
+    let unsigned_len = signature::verify(&file, keys)?;
+    parse_package(&file[..unsigned_len], options)
+}
 
-    let mut str = String::default();
-    let mut index = 0;
+pub(crate) fn parse_package(
+    file: &[u8],
+    options: &LoadOptions,
+) -> Result<Package, err::UnpackError> {
+    let mut reader = Cursor::new(file);
+    let header = read_header(&mut reader)?;
+    let cipher = header.cipher(options.key.as_ref(), &options.limits)?;
+    let limits = &options.limits;
+    let (map, data_start) = read_package_table(&mut reader, &header, cipher.as_ref(), limits)?;
+    let data = &file[data_start as usize..];
+    let declared_size = map
+        .values()
+        .filter_map(|info| info.original_size)
+        .try_fold(0u64, |total, size| total.checked_add(size));
+    if declared_size.is_none_or(|size| size > limits.max_total_size) {
+        return Err(err::LimitError::TotalSize(limits.max_total_size).into());
+    }
 
-    while let Some(Ok(b)) = bytes.next() {
-        if b == b'\0' && state == ParseState::String {
-            break;
+    let mut names = HashMap::new();
+    let mut entries = HashMap::new();
+    let mut metadata = HashMap::new();
+    let mut links = HashMap::new();
+    let mut dirs = HashSet::new();
+    let mut total_size = 0;
+    for (name, info) in map {
+        let stored = &data[info.index as usize..(info.index + info.size) as usize];
+        let d = decode_entry(
+            &name,
+            &info,
+            stored,
+            cipher.as_ref(),
+            options.verify_checksums,
+            limits.max_total_size - total_size,
+        )?;
+        total_size += d.len() as u64;
+        entries.insert(
+            name.clone(),
+            EntryInfo {
+                compression: info.compression,
+                stored_size: info.size,
+                original_size: d.len() as u64,
+                checksum: info.checksum,
+                kind: info.kind,
+            },
+        );
+        if let Some(m) = info.metadata {
+            metadata.insert(name.clone(), m);
         }
-        if state == ParseState::String {
-            let mut str_buf = vec![b];
-            while let Some(str_byte) = bytes.next() {
-                let str_byte = str_byte?;
-                if str_byte != b'\0' {
-                    str_buf.push(str_byte);
-                } else {
-                    break;
-                }
+        match info.kind {
+            EntryKind::File => {
+                names.insert(name, d);
             }
-            state = ParseState::Index;
-            str = String::from_utf8(str_buf)?;
-        } else if state == ParseState::Index {
-            let mut idx: [u8; 4] = [b; 4];
-            for i in 1..4 {
-                idx[i] = bytes.next().ok_or_else(|| err::ParseError::Index)??;
+            EntryKind::Symlink => {
+                let target =
+                    String::from_utf8(d).map_err(|source| err::UnpackError::LinkTargetError {
+                        name: name.clone(),
+                        source,
+                    })?;
+                links.insert(name, target);
             }
-            index = u32::from_le_bytes(idx);
-            state = ParseState::Size;
-        } else if state == ParseState::Size {
-            let mut sz: [u8; 4] = [b; 4];
-            for i in 1..4 {
-                sz[i] = bytes.next().ok_or_else(|| err::ParseError::Size)??;
+            EntryKind::Directory => {
+                dirs.insert(name);
             }
-            let size = u32::from_le_bytes(sz);
-            state = ParseState::String;
-            map.insert(str.clone(), DataInfo::new(index, size));
         }
     }
-    Ok((map, bytes))
+
+    Ok(Package {
+        names,
+        entries,
+        metadata,
+        links,
+        dirs,
+        version: header.version,
+        compression: header.compression,
+        compression_level: 0,
+        encryption: header.encryption(options.key.as_ref()),
+    })
+}
+
+pub fn unpack_to_dir(dir_path: PathBuf, pack: &Package) -> Result<(), err::UnpackError> {
+    unpack_to_dir_with(dir_path, pack, &UnpackOptions::default())
 }
 
-pub fn unpack_to_dir(dir_path: PathBuf, pack: &Package) -> std::io::Result<()> {
-    DirBuilder::new().recursive(true).create(dir_path.clone())?;
+/// Unpacks a package into `dir_path`. Nothing is ever written outside of `dir_path`, entries
+/// whose path leads through a symlink fail with [`UnpackError::UnsafePath`](err::UnpackError)
+pub fn unpack_to_dir_with(
+    dir_path: PathBuf,
+    pack: &Package,
+    options: &UnpackOptions,
+) -> Result<(), err::UnpackError> {
+    unpack_to_dir_with_progress(dir_path, pack, options, &mut NoProgress)
+}
+
+/// Unpacks a package like [`unpack_to_dir_with`], reporting every entry written to `progress`
+pub fn unpack_to_dir_with_progress(
+    dir_path: PathBuf,
+    pack: &Package,
+    options: &UnpackOptions,
+    progress: &mut dyn ProgressSink,
+) -> Result<(), err::UnpackError> {
+    let check_cancelled = || match options.cancel.is_cancelled() {
+        true => Err(err::UnpackError::Cancelled),
+        false => Ok(()),
+    };
+    let entries = pack.names.len() + pack.links.len() + pack.dirs.len();
+    let size = pack.names.values().map(|data| data.len() as u64).sum();
+    progress.total(entries, size);
+    DirBuilder::new()
+        .recursive(true)
+        .create(&dir_path)
+        .map_err(err::UnpackError::file(&dir_path))?;
+    for dir_name in &pack.dirs {
+        check_cancelled()?;
+        progress.entry_started(dir_name);
+        prepare_path(&dir_path, dir_name, true)?;
+        progress.entry_finished(dir_name);
+    }
     for file_name in pack.names.keys() {
+        check_cancelled()?;
+        progress.entry_started(file_name);
         let bytes = pack.get_data_ref(file_name).unwrap();
-        let mut path = dir_path.clone();
-        path.push(file_name);
-        if let Some(prefix) = path.parent() {
-            fs::create_dir_all(prefix)?;
+        let path = prepare_path(&dir_path, file_name, false)?;
+        let mut file = fs::File::create(&path).map_err(err::UnpackError::file(&path))?;
+        file.write_all(bytes)
+            .map_err(err::UnpackError::file(&path))?;
+        if let Some(metadata) = pack.metadata(file_name) {
+            restore_metadata(&path, Some(file), metadata, options)
+                .map_err(err::UnpackError::file(&path))?;
         }
-        let mut file = fs::File::create(path)?;
-        file.write_all(bytes)?;
+        progress.bytes_processed(bytes.len() as u64);
+        progress.entry_finished(file_name);
+    }
+    // links come last so they can not redirect the files written above
+    for (name, target) in &pack.links {
+        check_cancelled()?;
+        progress.entry_started(name);
+        if !link_stays_inside(name, target, pack) {
+            return Err(err::UnpackError::UnsafePath { name: name.clone() });
+        }
+        let path = prepare_path(&dir_path, name, false)?;
+        if path.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(&path).map_err(err::UnpackError::file(&path))?;
+        }
+        create_symlink(target, &path).map_err(err::UnpackError::file(&path))?;
+        progress.entry_finished(name);
+    }
+    // directories are finished last, deepest first, since writing into them changes their
+    // modification time and restored permissions may not allow writing at all
+    let mut dirs = pack.dirs.iter().collect::<Vec<_>>();
+    dirs.sort_unstable_by(|a, b| b.cmp(a));
+    for dir_name in dirs {
+        if let Some(metadata) = pack.metadata(dir_name) {
+            let path = dir_path.join(dir_name);
+            #[cfg(unix)]
+            let dir = Some(fs::File::open(&path).map_err(err::UnpackError::file(&path))?);
+            // directories can not be opened as files elsewhere, so their mtime is not restored
+            #[cfg(not(unix))]
+            let dir = None;
+            restore_metadata(&path, dir, metadata, options)
+                .map_err(err::UnpackError::file(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the missing parent directories of the entry `name` below `dir_path` one at a time
+/// and returns the path of the entry. Fails if the entry is not a plain relative path or one of
+/// its parents is a symlink, which could lead anywhere. A symlink in place of the entry itself
+/// is removed, so it is replaced instead of written through
+fn prepare_path(dir_path: &Path, name: &str, is_dir: bool) -> Result<PathBuf, err::UnpackError> {
+    let unsafe_path = || err::UnpackError::UnsafePath {
+        name: name.to_string(),
+    };
+    validate_name(name).map_err(|_| unsafe_path())?;
+    let components = name.split('/').collect::<Vec<_>>();
+    let mut path = dir_path.to_path_buf();
+    for (i, component) in components.iter().enumerate() {
+        path.push(component);
+        let is_entry = i + 1 == components.len();
+        match path.symlink_metadata() {
+            Ok(m) if m.file_type().is_symlink() => {
+                if !is_entry {
+                    return Err(unsafe_path());
+                }
+                fs::remove_file(&path).map_err(err::UnpackError::file(&path))?;
+                if is_dir {
+                    fs::create_dir(&path).map_err(err::UnpackError::file(&path))?;
+                }
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !is_entry || is_dir {
+                    fs::create_dir(&path).map_err(err::UnpackError::file(&path))?;
+                }
+            }
+            Err(e) => return Err(err::UnpackError::file(&path)(e)),
+        }
+    }
+    Ok(path)
+}
+
+/// Whether the symlink entry `name` pointing to `target` stays inside of the directory the
+/// package is unpacked to. Going up from another symlink entry is refused, since where that
+/// leads depends on the other link
+fn link_stays_inside(name: &str, target: &str, pack: &Package) -> bool {
+    let target = Path::new(target);
+    if target.has_root() || target.is_absolute() {
+        return false;
+    }
+    let mut resolved = name.split('/').collect::<Vec<_>>();
+    resolved.pop();
+    for component in target.components() {
+        match component {
+            std::path::Component::Normal(c) => match c.to_str() {
+                Some(c) => resolved.push(c),
+                None => return false,
+            },
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if resolved.is_empty() || pack.links.contains_key(&resolved.join("/")) {
+                    return false;
+                }
+                resolved.pop();
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    let target = PathBuf::from_slash(target);
+    let resolved = path.parent().map(|p| p.join(&target));
+    if resolved.is_some_and(|r| r.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &str, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+/// Applies the stored metadata of an unpacked file, as far as `options` ask for it. The
+/// modification time is set through `file`, when there is one
+fn restore_metadata(
+    path: &Path,
+    file: Option<fs::File>,
+    metadata: EntryMetadata,
+    options: &UnpackOptions,
+) -> std::io::Result<()> {
+    if let Some(file) = file.filter(|_| options.restore_metadata) {
+        let mtime = match u64::try_from(metadata.mtime) {
+            Ok(secs) => std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs),
+            Err(_) => {
+                std::time::UNIX_EPOCH
+                    - std::time::Duration::from_secs(metadata.mtime.unsigned_abs())
+            }
+        };
+        file.set_modified(mtime)?;
+    }
+    #[cfg(unix)]
+    if options.restore_ownership {
+        std::os::unix::fs::chown(path, Some(metadata.uid), Some(metadata.gid))?;
+    }
+    // permissions come last, changing the owner may clear the setuid and setgid bits
+    if options.restore_metadata {
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            let mask = match options.restore_special_bits {
+                true => 0o7777,
+                false => 0o777,
+            };
+            fs::Permissions::from_mode(metadata.mode & mask)
+        };
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_readonly(metadata.mode & 0o222 == 0);
+            permissions
+        };
+        fs::set_permissions(path, permissions)?;
     }
     Ok(())
 }
@@ -0,0 +1,159 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use memmap2::Mmap;
+
+use crate::encryption::Cipher;
+use crate::err::UnpackError;
+use crate::package::DataInfo;
+use crate::parse::{decode_entry, read_header, read_package_table};
+use crate::{Compression, EntryKind, EntryMetadata, EntryReader, LoadOptions, PackageVersion};
+
+/// A package backed by a memory-mapped file. Entries stored without compression or encryption
+/// are handed out as slices of the map without copying them
+pub struct MappedPackage {
+    map: Mmap,
+    /// Position of the data blob inside of the map
+    data_start: usize,
+    table: HashMap<String, DataInfo>,
+    version: PackageVersion,
+    compression: Compression,
+    cipher: Option<Cipher>,
+    verify_checksums: bool,
+    /// Largest size an entry may decompress to
+    max_entry_size: u64,
+}
+
+impl MappedPackage {
+    /// Maps a package file, parsing only its header and data table
+    pub fn open(path: PathBuf) -> Result<Self, UnpackError> {
+        Self::open_with(path, &LoadOptions::default())
+    }
+    pub fn open_with(path: PathBuf, options: &LoadOptions) -> Result<Self, UnpackError> {
+        let file = fs::File::open(&path).map_err(UnpackError::file(&path))?;
+        // SAFETY: the map is only ever read, modifying the file while it is mapped is not
+        // supported, just like for any other reader of the package
+        let map = unsafe { Mmap::map(&file) }.map_err(UnpackError::file(&path))?;
+
+        let mut reader = Cursor::new(&map[..]);
+        let header = read_header(&mut reader)?;
+        let cipher = header.cipher(options.key.as_ref(), &options.limits)?;
+        let (table, data_start) =
+            read_package_table(&mut reader, &header, cipher.as_ref(), &options.limits)?;
+        Ok(Self {
+            map,
+            data_start: data_start as usize,
+            table,
+            version: header.version,
+            compression: header.compression,
+            cipher,
+            verify_checksums: options.verify_checksums,
+            max_entry_size: options.limits.max_total_size,
+        })
+    }
+    pub fn has(&self, name: &str) -> bool {
+        self.table.contains_key(name)
+    }
+    /// Reads the target of a symlink entry, returns `Ok(None)` if there is no such symlink
+    pub fn link_target(&self, name: &str) -> Result<Option<String>, UnpackError> {
+        let Some(info) = self
+            .table
+            .get(name)
+            .filter(|i| i.kind == EntryKind::Symlink)
+        else {
+            return Ok(None);
+        };
+        let target = decode_entry(
+            name,
+            info,
+            self.stored(info),
+            self.cipher.as_ref(),
+            self.verify_checksums,
+            self.max_entry_size,
+        )?;
+        let target = String::from_utf8(target).map_err(|source| UnpackError::LinkTargetError {
+            name: name.to_string(),
+            source,
+        })?;
+        Ok(Some(target))
+    }
+    pub fn kind(&self, name: &str) -> Option<EntryKind> {
+        Some(self.table.get(name)?.kind)
+    }
+    /// The table record of a regular file entry
+    fn file(&self, name: &str) -> Option<&DataInfo> {
+        self.table.get(name).filter(|i| i.kind == EntryKind::File)
+    }
+    fn stored(&self, info: &DataInfo) -> &[u8] {
+        let start = self.data_start + info.index as usize;
+        &self.map[start..start + info.size as usize]
+    }
+    /// Borrows the data of an entry straight from the map, returns `None` if there is no such
+    /// entry or it is compressed or encrypted, use [`MappedPackage::get_data`] for those
+    pub fn get_data_ref(&self, name: &str) -> Option<&[u8]> {
+        let info = self.file(name)?;
+        if info.compression != Compression::None || self.cipher.is_some() {
+            return None;
+        }
+        let data = self.stored(info);
+        if self.verify_checksums && info.checksum.is_some_and(|c| c != crc32c::crc32c(data)) {
+            return None;
+        }
+        Some(data)
+    }
+    /// Returns the data of an entry, borrowed from the map when possible and decoded otherwise
+    pub fn get_data(&self, name: &str) -> Option<Cow<'_, [u8]>> {
+        self.try_get_data(name).ok().flatten()
+    }
+    /// Like [`MappedPackage::get_data`], returns `Ok(None)` if there is no such entry
+    pub fn try_get_data(&self, name: &str) -> Result<Option<Cow<'_, [u8]>>, UnpackError> {
+        let Some(info) = self.file(name) else {
+            return Ok(None);
+        };
+        if let Some(data) = self.get_data_ref(name) {
+            return Ok(Some(Cow::Borrowed(data)));
+        }
+        let data = decode_entry(
+            name,
+            info,
+            self.stored(info),
+            self.cipher.as_ref(),
+            self.verify_checksums,
+            self.max_entry_size,
+        )?;
+        Ok(Some(Cow::Owned(data)))
+    }
+    /// Opens a stream over the data of an entry, returns `Ok(None)` if there is no such entry.
+    /// Entries are decompressed as the stream is read, except for entries that are encrypted, LZ4
+    /// compressed or checked against their checksum, which are decoded up front
+    pub fn open_entry(&self, name: &str) -> Result<Option<EntryReader<'_>>, UnpackError> {
+        let Some(info) = self.file(name) else {
+            return Ok(None);
+        };
+        if self.cipher.is_some() || self.verify_checksums || !info.compression.can_stream() {
+            return Ok(self.try_get_data(name)?.map(EntryReader::from_memory));
+        }
+        let stored = self.stored(info);
+        let entry = EntryReader::decoding(info.compression, info.original_size, move || {
+            Cursor::new(stored)
+        })?;
+        Ok(Some(entry))
+    }
+    /// Returns the file metadata stored with an entry, if it has any
+    pub fn metadata(&self, name: &str) -> Option<EntryMetadata> {
+        self.table.get(name)?.metadata
+    }
+    /// The names of all entries, in no particular order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.table.keys().map(|k| k.as_str())
+    }
+    pub fn version(&self) -> PackageVersion {
+        self.version
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{CancellationToken, EncryptionKey, EntryMetadata};
+
+/// Options controlling how a package file is loaded
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /// Fail with [`UnpackError::ChecksumMismatch`](crate::err::UnpackError::ChecksumMismatch)
+    /// when an entry does not match its stored checksum, instead of leaving it to
+    /// [`Package::verify`](crate::Package::verify)
+    pub verify_checksums: bool,
+    /// The key encrypted packages are decrypted with
+    pub key: Option<EncryptionKey>,
+    /// Bounds on the size of the package, checked before anything is allocated for it
+    pub limits: LoadLimits,
+}
+
+impl LoadOptions {
+    /// Options that reject packages with corrupted entries
+    pub fn strict() -> Self {
+        Self {
+            verify_checksums: true,
+            key: None,
+            limits: LoadLimits::default(),
+        }
+    }
+}
+
+/// Upper bounds on what a package may contain to be loaded, protecting against crafted packages
+/// that would exhaust memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadLimits {
+    /// Maximum number of entries in the data table
+    pub max_entries: usize,
+    /// Maximum length of an entry name in bytes
+    pub max_name_len: usize,
+    /// Maximum size of the data of all entries once decompressed. Readers that load entries on
+    /// demand apply it to every entry on its own
+    pub max_total_size: u64,
+    /// Maximum memory in KiB the key derivation of a passphrase encrypted package may use
+    pub max_kdf_memory: u32,
+    /// Maximum number of passes the key derivation may make over its memory
+    pub max_kdf_passes: u32,
+    /// Maximum number of lanes the key derivation may use
+    pub max_kdf_lanes: u32,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 1 << 20,
+            max_name_len: 4096,
+            max_total_size: 4 << 30,
+            max_kdf_memory: 1 << 20,
+            max_kdf_passes: 16,
+            max_kdf_lanes: 16,
+        }
+    }
+}
+
+impl LoadLimits {
+    /// Limits that accept any package
+    pub fn unlimited() -> Self {
+        Self {
+            max_entries: usize::MAX,
+            max_name_len: usize::MAX,
+            max_total_size: u64::MAX,
+            max_kdf_memory: u32::MAX,
+            max_kdf_passes: u32::MAX,
+            max_kdf_lanes: u32::MAX,
+        }
+    }
+}
+
+/// Options controlling how a package file is written
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Keep the file being replaced as `<path>.bak`
+    pub backup: bool,
+    /// Number of threads compressing and encrypting the entries, 0 uses one per CPU core. The
+    /// package is the same whatever the number of threads
+    pub threads: usize,
+    /// Stops writing the package, leaving any previous file in place
+    pub cancel: CancellationToken,
+}
+
+/// Options controlling how a package is unpacked into a directory
+#[derive(Clone, Debug, Default)]
+pub struct UnpackOptions {
+    /// Restore the permissions and modification times stored with the entries
+    pub restore_metadata: bool,
+    /// Restore the owner and group stored with the entries, which usually requires root
+    /// privileges. Has no effect on platforms other than Unix
+    pub restore_ownership: bool,
+    /// Also restore the setuid, setgid and sticky bits along with the permissions, which lets a
+    /// package grant privileges to its files. Meant for trusted packages unpacked with their
+    /// ownership, has no effect without `restore_metadata`
+    pub restore_special_bits: bool,
+    /// Stops unpacking, the entries unpacked so far are left in the directory
+    pub cancel: CancellationToken,
+}
+
+/// What to do with symbolic links found while packing a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Package the file or directory the link points to, dangling links are skipped
+    #[default]
+    Follow,
+    /// Store the link itself as a symlink entry
+    Link,
+    /// Leave links out of the package
+    Skip,
+}
+
+/// Options controlling how a directory is packaged
+#[derive(Clone, Debug, Default)]
+pub struct PackOptions {
+    pub symlinks: SymlinkPolicy,
+    /// Patterns of the files to package, in the syntax of `.m3ignore` files. Files are also
+    /// included when one of the directories they are in matches, everything is included when
+    /// there are no patterns
+    pub include: Vec<String>,
+    /// Patterns of the files and directories to leave out, in the syntax of `.m3ignore` files.
+    /// They are applied after the `.m3ignore` files of the directory
+    pub exclude: Vec<String>,
+    /// Modification times later than this Unix timestamp are stored as the timestamp instead,
+    /// like `SOURCE_DATE_EPOCH` asks for in reproducible builds
+    pub mtime_clamp: Option<i64>,
+    /// Number of threads reading and encoding the files, 0 uses one per CPU core. The package is
+    /// the same whatever the number of threads
+    pub threads: usize,
+    /// Stops packaging the directory
+    pub cancel: CancellationToken,
+}
+
+impl PackOptions {
+    /// The metadata a file is packaged with
+    pub(crate) fn entry_metadata(&self, metadata: &std::fs::Metadata) -> EntryMetadata {
+        let mut metadata = EntryMetadata::from_fs(metadata);
+        if let Some(clamp) = self.mtime_clamp {
+            metadata.mtime = metadata.mtime.min(clamp);
+        }
+        metadata
+    }
+}
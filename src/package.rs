@@ -1,21 +1,81 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Component, PathBuf};
 
-use path_slash::{PathBufExt, PathExt};
+use bytes::Bytes;
+use path_slash::PathBufExt;
 
 use super::err;
+use crate::{Encryption, EntryReader, LoadOptions};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PackageVersion {
     pub ver: (u8, u8, u8, u8),
 }
 
-impl Into<[u8; 4]> for PackageVersion {
-    fn into(self) -> [u8; 4] {
-        let ver = self.ver;
+impl PackageVersion {
+    /// Whether the data table of this version stores full 64-bit offsets and sizes
+    pub(crate) fn has_wide_table(&self) -> bool {
+        self.ver >= (0, 0, 1, 0)
+    }
+    /// Whether packages of this version can hold compressed entries
+    pub(crate) fn supports_compression(&self) -> bool {
+        self.ver >= (0, 0, 1, 0)
+    }
+    /// Whether the data table of this version stores the codec and original size of every entry
+    pub(crate) fn has_entry_codecs(&self) -> bool {
+        self.ver >= (0, 0, 2, 0)
+    }
+    /// Whether the data table of this version stores a checksum of every entry
+    pub(crate) fn has_checksums(&self) -> bool {
+        self.ver >= (0, 0, 3, 0)
+    }
+    /// Whether packages of this version can be encrypted
+    pub(crate) fn supports_encryption(&self) -> bool {
+        self.ver >= (0, 0, 4, 0)
+    }
+    /// Whether the data table of this version follows the data, located through a footer at the
+    /// end of the file
+    pub(crate) fn has_trailing_table(&self) -> bool {
+        self.ver >= (0, 0, 5, 0)
+    }
+    /// Whether the data table of this version can store the file metadata of every entry
+    pub(crate) fn has_metadata(&self) -> bool {
+        self.ver >= (0, 0, 6, 0)
+    }
+    /// Whether the data table of this version stores the type of every entry, allowing entries
+    /// other than regular files
+    pub(crate) fn has_entry_kinds(&self) -> bool {
+        self.ver >= (0, 0, 7, 0)
+    }
+    /// Whether this version can be read and written by this library
+    pub(crate) fn is_supported(&self) -> bool {
+        matches!(
+            self.ver,
+            (0, 0, 0, 1..)
+                | (0, 0, 1, 0)
+                | (0, 0, 2, 0)
+                | (0, 0, 3, 0)
+                | (0, 0, 4, 0)
+                | (0, 0, 5, 0)
+                | (0, 0, 6, 0)
+                | (0, 0, 7, 0)
+        )
+    }
+}
+
+impl From<PackageVersion> for [u8; 4] {
+    fn from(value: PackageVersion) -> Self {
+        let ver = value.ver;
         [ver.0, ver.1, ver.2, ver.3]
     }
 }
+impl std::fmt::Display for PackageVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ver = self.ver;
+        write!(f, "{}.{}.{}.{}", ver.0, ver.1, ver.2, ver.3)
+    }
+}
 impl From<(u8, u8, u8, u8)> for PackageVersion {
     fn from(value: (u8, u8, u8, u8)) -> Self {
         Self { ver: value }
@@ -25,7 +85,7 @@ impl TryFrom<&[u8]> for PackageVersion {
     type Error = err::ParseError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         if value.len() != 4 {
-            Err(err::ParseError::Version)
+            Err(err::ParseError::Version { len: value.len() })
         } else {
             Ok(Self {
                 ver: (value[0], value[1], value[2], value[3]),
@@ -37,55 +97,273 @@ impl TryFrom<&[u8]> for PackageVersion {
 pub struct FileInfo {
     path: PathBuf,
     data: Vec<u8>,
+    metadata: Option<EntryMetadata>,
 }
 impl FileInfo {
     pub fn new(path: PathBuf, data: Vec<u8>) -> Self {
-        Self { data, path }
+        Self {
+            data,
+            path,
+            metadata: None,
+        }
+    }
+    pub fn with_metadata(mut self, metadata: EntryMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+}
+
+/// File metadata stored along with an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryMetadata {
+    /// Unix permission bits, including the setuid, setgid and sticky bits
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+}
+impl EntryMetadata {
+    /// Size of the metadata block in the data table, not counting its presence byte
+    pub(crate) const STORED_LEN: usize = 20;
+
+    /// Captures the metadata of a file, platforms without Unix permissions get 0o644 or 0o444
+    /// for read-only files and no ownership
+    pub fn from_fs(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Self {
+                mode: metadata.mode() & 0o7777,
+                mtime: metadata.mtime(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as i64);
+            Self {
+                mode: if metadata.permissions().readonly() {
+                    0o444
+                } else {
+                    0o644
+                },
+                mtime,
+                uid: 0,
+                gid: 0,
+            }
+        }
+    }
+    pub(crate) fn to_bytes(self) -> [u8; Self::STORED_LEN] {
+        let mut buf = [0u8; Self::STORED_LEN];
+        buf[..4].copy_from_slice(&self.mode.to_le_bytes());
+        buf[4..12].copy_from_slice(&self.mtime.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.uid.to_le_bytes());
+        buf[16..].copy_from_slice(&self.gid.to_le_bytes());
+        buf
+    }
+    pub(crate) fn from_bytes(buf: &[u8; Self::STORED_LEN]) -> Self {
+        Self {
+            mode: u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            mtime: i64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            uid: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            gid: u32::from_le_bytes(buf[16..].try_into().unwrap()),
+        }
+    }
+}
+
+/// The type of an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    /// A symbolic link, its data is the link target
+    Symlink,
+    /// A directory, stored so empty directories and their metadata survive packing
+    Directory,
+}
+
+impl From<EntryKind> for u8 {
+    fn from(value: EntryKind) -> Self {
+        match value {
+            EntryKind::File => 0x00,
+            EntryKind::Symlink => 0x01,
+            EntryKind::Directory => 0x02,
+        }
+    }
+}
+impl TryFrom<u8> for EntryKind {
+    type Error = err::ParseError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(EntryKind::File),
+            0x01 => Ok(EntryKind::Symlink),
+            0x02 => Ok(EntryKind::Directory),
+            kind => Err(err::ParseError::EntryKind { kind }),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct DataInfo {
-    pub(crate) index: u32,
-    pub(crate) size: u32,
+    pub(crate) index: u64,
+    pub(crate) size: u64,
+    pub(crate) original_size: Option<u64>,
+    pub(crate) compression: Compression,
+    pub(crate) checksum: Option<u32>,
+    pub(crate) metadata: Option<EntryMetadata>,
+    pub(crate) kind: EntryKind,
 }
 impl DataInfo {
-    pub fn new(index: u32, size: u32) -> Self {
-        Self { index, size }
+    pub fn new(index: u64, size: u64, compression: Compression) -> Self {
+        Self {
+            index,
+            size,
+            original_size: None,
+            compression,
+            checksum: None,
+            metadata: None,
+            kind: EntryKind::File,
+        }
     }
 }
 
-#[derive(Clone, Copy)]
+/// Describes how an entry is stored inside of a package file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// The codec the entry was stored with
+    pub compression: Compression,
+    /// The size of the entry inside of the package file
+    pub stored_size: u64,
+    /// The size of the entry after decompression
+    pub original_size: u64,
+    /// The CRC32C checksum of the decompressed entry, if the package version stores one
+    pub checksum: Option<u32>,
+    /// What the entry is, the data of symlinks being their target and directories having none
+    pub kind: EntryKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub enum Compression {
     None,
+    Zstd,
+    /// zlib wrapped deflate streams
+    Deflate,
+    /// LZ4 blocks prefixed with their uncompressed size, the level is ignored
+    Lz4,
+}
+
+impl Compression {
+    /// Compresses the data of a single entry, a `level` of 0 selects the default level of the codec
+    pub(crate) fn compress(&self, data: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::compress(data, level),
+            Compression::Deflate => {
+                let level = match level {
+                    0 => flate2::Compression::default(),
+                    l => flate2::Compression::new(l.clamp(1, 9) as u32),
+                };
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+    /// Decompresses `data`, returns `Ok(None)` instead once it decompresses to more than `limit`
+    /// bytes
+    pub(crate) fn decompress(&self, data: &[u8], limit: u64) -> std::io::Result<Option<Vec<u8>>> {
+        if *self == Compression::Lz4 {
+            // LZ4 allocates the prepended size up front, so it is checked first
+            let size = data
+                .get(..4)
+                .map(|s| u32::from_le_bytes(s.try_into().unwrap()));
+            if size.is_some_and(|size| size as u64 > limit) {
+                return Ok(None);
+            }
+            // no LZ4 block expands by more than this, larger sizes are corrupted
+            if size.is_some_and(|size| size as u64 > data.len() as u64 * 255) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "LZ4 block is smaller than its stated size allows",
+                ));
+            }
+            return lz4_flex::decompress_size_prepended(data)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+        let mut buf = vec![];
+        self.decoder(data)?
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut buf)?;
+        Ok((buf.len() as u64 <= limit).then_some(buf))
+    }
+    /// Whether entries of this codec can be decompressed as they are read, LZ4 blocks have to be
+    /// decompressed as a whole
+    pub(crate) fn can_stream(&self) -> bool {
+        *self != Compression::Lz4
+    }
+    /// Wraps `reader` in a decoder that decompresses it as it is read, see
+    /// [`Compression::can_stream`]
+    pub(crate) fn decoder<'a, R: Read + 'a>(
+        &self,
+        reader: R,
+    ) -> std::io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::None => Ok(Box::new(reader)),
+            Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            Compression::Deflate => Ok(Box::new(flate2::read::ZlibDecoder::new(reader))),
+            Compression::Lz4 => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "LZ4 entries can not be decompressed as a stream",
+            )),
+        }
+    }
 }
 
 impl TryFrom<&[u8]> for Compression {
     type Error = err::ParseError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != 2 {
-            Err(err::ParseError::Compression)
-        } else {
-            match value {
-                [0x00, 0x00] => Ok(Self::None),
-                _ => Err(err::ParseError::Compression),
-            }
+        match value {
+            [0x00, 0x00] => Ok(Self::None),
+            [0x01, 0x00] => Ok(Self::Zstd),
+            [0x02, 0x00] => Ok(Self::Deflate),
+            [0x03, 0x00] => Ok(Self::Lz4),
+            codec => Err(err::ParseError::Compression {
+                codec: codec.to_vec(),
+            }),
         }
     }
 }
-impl Into<[u8; 2]> for Compression {
-    fn into(self) -> [u8; 2] {
-        match self {
+impl From<Compression> for [u8; 2] {
+    fn from(value: Compression) -> Self {
+        match value {
             Compression::None => [0x00, 0x00],
+            Compression::Zstd => [0x01, 0x00],
+            Compression::Deflate => [0x02, 0x00],
+            Compression::Lz4 => [0x03, 0x00],
         }
     }
 }
 
 pub struct Package {
     pub(crate) names: HashMap<String, Vec<u8>>,
+    pub(crate) entries: HashMap<String, EntryInfo>,
+    pub(crate) metadata: HashMap<String, EntryMetadata>,
+    /// Symlink entries and their targets
+    pub(crate) links: HashMap<String, String>,
+    /// Directory entries
+    pub(crate) dirs: HashSet<String>,
     pub(crate) version: PackageVersion,
     pub(crate) compression: Compression,
+    pub(crate) compression_level: i32,
+    pub(crate) encryption: Option<Encryption>,
 }
 
 impl Package {
@@ -95,17 +373,66 @@ impl Package {
         compression: Compression,
     ) -> Self {
         let mut map = HashMap::new();
+        let mut metadata = HashMap::new();
         for file_info in value {
-            map.insert(file_info.path.to_string_lossy().to_string(), file_info.data);
+            let name = file_info.path.to_string_lossy().to_string();
+            if let Some(m) = file_info.metadata {
+                metadata.insert(name.clone(), m);
+            }
+            map.insert(name, file_info.data);
         }
         Package {
             names: map,
+            entries: HashMap::new(),
+            metadata,
+            links: HashMap::new(),
+            dirs: HashSet::new(),
             version,
             compression,
+            compression_level: 0,
+            encryption: None,
         }
     }
+    /// Loads a package from a reader such as a socket, reading it to its end
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, err::UnpackError> {
+        Self::from_reader_with(reader, &LoadOptions::default())
+    }
+    pub fn from_reader_with<R: Read>(
+        mut reader: R,
+        options: &LoadOptions,
+    ) -> Result<Self, err::UnpackError> {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+        crate::parse_package(&buf, options)
+    }
+    /// Loads a package from a buffer holding a whole package file
+    pub fn from_bytes(bytes: Bytes) -> Result<Self, err::UnpackError> {
+        crate::parse_package(&bytes, &LoadOptions::default())
+    }
+    /// Writes the package file to `writer`, which unlike [`write_package`](crate::write_package)
+    /// does not have to be a file
+    pub fn write_to<W: Write>(&mut self, mut writer: W) -> Result<(), err::WriteError> {
+        let buf = crate::serialize_package(
+            self,
+            &crate::WriteOptions::default(),
+            &mut crate::NoProgress,
+        )?;
+        writer.write_all(&buf)?;
+        Ok(())
+    }
     pub fn has(&self, name: &str) -> bool {
-        self.names.contains_key(name)
+        self.names.contains_key(name) || self.links.contains_key(name) || self.dirs.contains(name)
+    }
+    pub fn kind(&self, name: &str) -> Option<EntryKind> {
+        if self.names.contains_key(name) {
+            Some(EntryKind::File)
+        } else if self.links.contains_key(name) {
+            Some(EntryKind::Symlink)
+        } else if self.dirs.contains(name) {
+            Some(EntryKind::Directory)
+        } else {
+            None
+        }
     }
     pub fn get_data(&self, name: &str) -> Option<Vec<u8>> {
         self.names.get(name).cloned()
@@ -113,38 +440,162 @@ impl Package {
     pub fn get_data_ref(&self, name: &str) -> Option<&[u8]> {
         self.names.get(name).map(|v| v.as_slice())
     }
+    /// Opens a stream over the data of an entry without copying it
+    pub fn open(&self, name: &str) -> Option<EntryReader<'_>> {
+        self.get_data_ref(name).map(EntryReader::from_memory)
+    }
     pub fn version(&self) -> PackageVersion {
         self.version
     }
+    /// Sets the format version the package will be written as
+    pub fn set_version(&mut self, version: PackageVersion) {
+        self.version = version;
+    }
     pub fn compression(&self) -> Compression {
         self.compression
     }
+    /// Sets the compression applied to every entry when the package is written
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level
+    }
+    /// Sets the level used by the compression codec, 0 selects the default level of the codec
+    pub fn set_compression_level(&mut self, level: i32) {
+        self.compression_level = level;
+    }
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+    /// Sets the encryption applied when the package is written, `None` writes it unencrypted
+    pub fn set_encryption(&mut self, encryption: Option<Encryption>) {
+        self.encryption = encryption;
+    }
     pub fn get_files(&self) -> &HashMap<String, Vec<u8>> {
         &self.names
     }
-    pub fn insert_data(&mut self, name: String, data: Vec<u8>) -> Result<(), err::InsertError> {
-        let has_moves = name.contains("..");
-        if has_moves {
-            return Err(err::InsertError::ProhibitedPath);
-        }
-        let as_path = PathBuf::from(&name);
-        let as_path: PathBuf = as_path
-            .to_slash()
-            .ok_or(err::InsertError::NotAFilePath)?
-            .to_string()
-            .into();
-        let has_root = as_path.has_root();
-        let is_absolute = as_path.is_absolute();
-        let is_file = as_path.file_stem().is_some();
-        if has_root || is_absolute || !is_file {
-            return Err(err::InsertError::ProhibitedPath);
+    /// The symlink entries of the package and their targets
+    pub fn links(&self) -> &HashMap<String, String> {
+        &self.links
+    }
+    pub fn link_target(&self, name: &str) -> Option<&str> {
+        self.links.get(name).map(|t| t.as_str())
+    }
+    /// The directory entries of the package. Directories only holding files do not need an
+    /// entry of their own, but packaged directories always get one
+    pub fn directories(&self) -> &HashSet<String> {
+        &self.dirs
+    }
+    /// Returns how an entry of any kind is stored in the package file this package was loaded
+    /// from or last written to, entries that were not written yet are reported as stored
+    /// uncompressed
+    pub fn entry_info(&self, name: &str) -> Option<EntryInfo> {
+        if let Some(info) = self.entries.get(name) {
+            return Some(*info);
+        }
+        let kind = self.kind(name)?;
+        let size = match kind {
+            EntryKind::File => self.names[name].len(),
+            EntryKind::Symlink => self.links[name].len(),
+            EntryKind::Directory => 0,
+        } as u64;
+        Some(EntryInfo {
+            compression: Compression::None,
+            stored_size: size,
+            original_size: size,
+            checksum: None,
+            kind,
+        })
+    }
+    /// Returns the file metadata stored with an entry, if it has any
+    pub fn metadata(&self, name: &str) -> Option<EntryMetadata> {
+        self.metadata.get(name).copied()
+    }
+    /// Sets the file metadata of an existing entry, only package versions 0.0.6.0 and newer
+    /// store it
+    pub fn set_metadata(&mut self, name: &str, metadata: Option<EntryMetadata>) {
+        if !self.has(name) {
+            return;
         }
+        match metadata {
+            Some(m) => self.metadata.insert(name.to_string(), m),
+            None => self.metadata.remove(name),
+        };
+    }
+    /// Checks the data of every entry against the checksum stored in the package file and
+    /// returns the sorted names of the entries that do not match
+    pub fn verify(&self) -> Vec<String> {
+        let mut corrupted = self
+            .names
+            .iter()
+            .filter(|(name, data)| {
+                self.entries
+                    .get(*name)
+                    .and_then(|info| info.checksum)
+                    .is_some_and(|checksum| checksum != crc32c::crc32c(data))
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        corrupted.sort();
+        corrupted
+    }
+    pub fn insert_data(&mut self, name: String, data: Vec<u8>) -> Result<(), err::InsertError> {
+        validate_name(&name)?;
+        self.remove_data(&name);
         self.names.insert(name, data);
         Ok(())
     }
+    /// Inserts a directory entry, replacing any entry with the same name. Only package versions
+    /// 0.0.7.0 and newer store directories, older ones leave them out
+    pub fn insert_dir(&mut self, name: String) -> Result<(), err::InsertError> {
+        let name = name.trim_end_matches('/').to_string();
+        validate_name(&name)?;
+        self.remove_data(&name);
+        self.dirs.insert(name);
+        Ok(())
+    }
+    /// Inserts a symlink entry pointing to `target`, replacing any entry with the same name.
+    /// Only package versions 0.0.7.0 and newer can store symlinks
+    pub fn insert_link(&mut self, name: String, target: String) -> Result<(), err::InsertError> {
+        validate_name(&name)?;
+        if target.is_empty() {
+            return Err(err::InsertError::NotAFilePath);
+        }
+        self.remove_data(&name);
+        self.links.insert(name, target);
+        Ok(())
+    }
     pub fn remove_data(&mut self, name: &str) {
         self.names.remove(name);
+        self.links.remove(name);
+        self.dirs.remove(name);
+        self.entries.remove(name);
+        self.metadata.remove(name);
+    }
+}
+
+/// Checks that `name` is a relative file path that stays inside of the package. Its components
+/// are checked, so `..` is only refused as a whole component and not inside of names like
+/// `v1..2.txt`
+pub(crate) fn validate_name(name: &str) -> Result<(), err::InsertError> {
+    let as_path = PathBuf::from(name);
+    let as_path: PathBuf = as_path
+        .to_slash()
+        .ok_or(err::InsertError::NotAFilePath)?
+        .to_string()
+        .into();
+    let has_root = as_path.has_root();
+    let is_absolute = as_path.is_absolute();
+    let is_file = as_path.file_stem().is_some();
+    // drive prefixes replace the path they are pushed onto on Windows
+    let only_names = as_path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if has_root || is_absolute || !is_file || !only_names || name.contains('\0') {
+        return Err(err::InsertError::ProhibitedPath);
     }
+    Ok(())
 }
 
 impl std::fmt::Debug for Package {
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+
+/// How many items each thread may run ahead of the items handed on in order, bounding how many
+/// results are held in memory at once
+const ITEMS_AHEAD: usize = 4;
+
+/// The number of threads to use when `threads` were asked for, 0 meaning one per CPU core
+pub(crate) fn thread_count(threads: usize) -> usize {
+    match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    }
+}
+
+/// Maps `items` on up to `threads` threads and hands the results to `sink` in the order of the
+/// items, so the outcome is the same as mapping them one after another. Stops at the first
+/// error of either
+pub(crate) fn map_ordered<T, R, E>(
+    items: Vec<T>,
+    threads: usize,
+    map: impl Fn(T) -> Result<R, E> + Sync,
+    mut sink: impl FnMut(R) -> Result<(), E>,
+) -> Result<(), E>
+where
+    T: Send,
+    R: Send,
+    E: Send,
+{
+    let threads = thread_count(threads).min(items.len());
+    if threads <= 1 {
+        for item in items {
+            sink(map(item)?)?;
+        }
+        return Ok(());
+    }
+
+    let window = threads * ITEMS_AHEAD;
+    let queue = Mutex::new(items.into_iter().enumerate());
+    // the number of results handed to the sink so far
+    let sunk = Mutex::new(0);
+    let progressed = Condvar::new();
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let (queue, sunk, progressed, stop, map) = (&queue, &sunk, &progressed, &stop, &map);
+            scope.spawn(move || loop {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some((i, item)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let mut done = sunk.lock().unwrap();
+                while i >= *done + window && !stop.load(Ordering::Relaxed) {
+                    done = progressed.wait(done).unwrap();
+                }
+                drop(done);
+                if tx.send((i, map(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = HashMap::new();
+        let mut next = 0;
+        let res = rx.iter().try_for_each(|(i, result)| {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&next) {
+                sink(result?)?;
+                next += 1;
+                *sunk.lock().unwrap() = next;
+                progressed.notify_all();
+            }
+            Ok(())
+        });
+        if res.is_err() {
+            stop.store(true, Ordering::Relaxed);
+            // wake the threads waiting for room, so they see they should stop
+            let _guard = sunk.lock().unwrap();
+            progressed.notify_all();
+        }
+        drop(rx);
+        res
+    })
+}
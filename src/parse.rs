@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+
+use crate::encryption::{Cipher, KeyDerivation, ENTRIES_ENCRYPTED, TABLE_ENCRYPTED};
+use crate::err::{self, LimitError, UnpackError, UnsupportedError};
+use crate::package::{validate_name, DataInfo, EntryKind, EntryMetadata};
+use crate::signature;
+use crate::{Compression, Encryption, EncryptionKey, LoadLimits, PackageVersion, FILE_HEADER};
+
+/// Marks the end of a package with a trailing data table, preceded by the offset of the table
+/// from the start of the data blob
+pub(crate) const FOOTER_MAGIC: [u8; 4] = [0x4D, 0x33, 0x49, 0x58];
+pub(crate) const FOOTER_LEN: u64 = 8 + FOOTER_MAGIC.len() as u64;
+
+/// Everything in front of the data table of a package file
+pub(crate) struct Header {
+    pub(crate) version: PackageVersion,
+    pub(crate) compression: Compression,
+    pub(crate) flags: u8,
+    pub(crate) kdf: Option<KeyDerivation>,
+}
+
+impl Header {
+    /// The header as it is stored in the file, authenticated along with an encrypted data table
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = FILE_HEADER.to_vec();
+        let ver: [u8; 4] = self.version.into();
+        buf.extend_from_slice(&ver);
+        let comp: [u8; 2] = self.compression.into();
+        buf.extend_from_slice(&comp);
+        if self.version.supports_encryption() {
+            buf.push(self.flags);
+        }
+        if let Some(kdf) = &self.kdf {
+            kdf.write(&mut buf);
+        }
+        buf
+    }
+    /// Derives the cipher of an encrypted package from `key`, unless the key derivation costs
+    /// more than `limits` allow
+    pub(crate) fn cipher(
+        &self,
+        key: Option<&EncryptionKey>,
+        limits: &LoadLimits,
+    ) -> Result<Option<Cipher>, UnpackError> {
+        match &self.kdf {
+            Some(kdf) => {
+                let key = key.ok_or(UnpackError::KeyRequired)?;
+                kdf.check_limits(limits)?;
+                Ok(Some(kdf.derive(key)?))
+            }
+            None => Ok(None),
+        }
+    }
+    pub(crate) fn encryption(&self, key: Option<&EncryptionKey>) -> Option<Encryption> {
+        self.kdf.as_ref()?;
+        Some(Encryption {
+            key: key?.clone(),
+            encrypt_table: self.flags & TABLE_ENCRYPTED != 0,
+        })
+    }
+}
+
+/// Fills `buf` from `reader`, which is at `offset` of the package, failing with
+/// [`UnpackError::InvalidFile`] when the package ends first
+fn read_exact_or_invalid<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    offset: u64,
+    reason: &'static str,
+) -> Result<(), UnpackError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => UnpackError::InvalidFile { offset, reason },
+        _ => e.into(),
+    })
+}
+
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<Header, UnpackError> {
+    let mut buf = [0u8; 10];
+    read_exact_or_invalid(reader, &mut buf, 0, "the header is incomplete")?;
+    if buf[..4] != FILE_HEADER {
+        return Err(UnpackError::InvalidFile {
+            offset: 0,
+            reason: "the header does not start with the package magic",
+        });
+    }
+    let version = PackageVersion::try_from(&buf[4..8])?;
+    let compression = Compression::try_from(&buf[8..10])?;
+
+    if !version.is_supported() {
+        return Err(UnsupportedError::Version { version }.into());
+    }
+    if compression != Compression::None && !version.supports_compression() {
+        return Err(UnpackError::UnsupportedFormat(
+            UnsupportedError::Compression,
+        ));
+    }
+
+    let mut flags = 0;
+    let mut kdf = None;
+    if version.supports_encryption() {
+        let mut buf = [0u8; 1];
+        read_exact_or_invalid(reader, &mut buf, 10, "the header is incomplete")?;
+        flags = buf[0];
+        if flags & !(ENTRIES_ENCRYPTED | TABLE_ENCRYPTED) != 0 {
+            return Err(UnpackError::UnsupportedFormat(UnsupportedError::Encryption));
+        }
+        if flags & ENTRIES_ENCRYPTED != 0 {
+            // behind the magic, version, compression and flags
+            kdf = Some(KeyDerivation::read(reader, 11)?);
+        }
+    }
+    Ok(Header {
+        version,
+        compression,
+        flags,
+        kdf,
+    })
+}
+
+/// Reads the data table at `offset` of the package, decrypting it first if it is encrypted
+fn read_table<R: BufRead>(
+    reader: &mut R,
+    offset: u64,
+    header: &Header,
+    cipher: Option<&Cipher>,
+    limits: &LoadLimits,
+) -> Result<HashMap<String, DataInfo>, UnpackError> {
+    let (version, compression) = (header.version, header.compression);
+    match cipher {
+        Some(cipher) if header.flags & TABLE_ENCRYPTED != 0 => {
+            let mut len = [0u8; 8];
+            let reason = "the length of the encrypted data table is missing";
+            read_exact_or_invalid(reader, &mut len, offset, reason)?;
+            let table_len = u64::from_le_bytes(len);
+            let mut table = vec![];
+            reader.by_ref().take(table_len).read_to_end(&mut table)?;
+            if table.len() as u64 != table_len {
+                let offset = offset + len.len() as u64 + table.len() as u64;
+                return Err(err::ParseError::Truncated { offset }.into());
+            }
+            let table = cipher
+                .decrypt(&table, &header.to_bytes())
+                .ok_or(UnpackError::TableDecryptionError)?;
+            read_data_table(&mut &table[..], 0, version, compression, limits)
+        }
+        _ => read_data_table(reader, offset, version, compression, limits),
+    }
+}
+
+/// Reads the data table of a package whose header was just read from `reader`, wherever the
+/// version places it, and returns it along with the position of the data blob. Every entry is
+/// checked to lie inside of the data blob without overlapping another one
+pub(crate) fn read_package_table<R: BufRead + Seek>(
+    reader: &mut R,
+    header: &Header,
+    cipher: Option<&Cipher>,
+    limits: &LoadLimits,
+) -> Result<(HashMap<String, DataInfo>, u64), UnpackError> {
+    if !header.version.has_trailing_table() {
+        let table_start = reader.stream_position()?;
+        let table = read_table(reader, table_start, header, cipher, limits)?;
+        let data_start = reader.stream_position()?;
+        let data_len = reader.seek(SeekFrom::End(0))? - data_start;
+        check_entry_bounds(&table, data_start, data_len)?;
+        return Ok((table, data_start));
+    }
+    let data_start = reader.stream_position()?;
+    let mut end = reader.seek(SeekFrom::End(0))?;
+    if end >= data_start + signature::TRAILER_LEN as u64 {
+        // the footer is in front of the signature trailer of signed packages
+        let mut magic = [0u8; 4];
+        reader.seek(SeekFrom::Start(end - magic.len() as u64))?;
+        reader.read_exact(&mut magic)?;
+        if magic == signature::SIGNATURE_MAGIC {
+            end -= signature::TRAILER_LEN as u64;
+        }
+    }
+    let footer_start = end
+        .checked_sub(FOOTER_LEN)
+        .filter(|start| *start >= data_start)
+        .ok_or(UnpackError::InvalidFile {
+            offset: end,
+            reason: "the package ends before its footer",
+        })?;
+    reader.seek(SeekFrom::Start(footer_start))?;
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    reader.read_exact(&mut footer)?;
+    let (table_offset, magic) = footer.split_at(8);
+    if magic != FOOTER_MAGIC {
+        return Err(UnpackError::InvalidFile {
+            offset: footer_start + 8,
+            reason: "the footer does not end with the footer magic",
+        });
+    }
+    let table_offset = u64::from_le_bytes(table_offset.try_into().unwrap());
+    let table_start = data_start
+        .checked_add(table_offset)
+        .filter(|start| *start <= footer_start)
+        .ok_or(UnpackError::InvalidFile {
+            offset: footer_start,
+            reason: "the footer points past the end of the package",
+        })?;
+    reader.seek(SeekFrom::Start(table_start))?;
+    let mut table_reader = reader.by_ref().take(footer_start - table_start);
+    let table = read_table(&mut table_reader, table_start, header, cipher, limits)?;
+    check_entry_bounds(&table, data_start, table_offset)?;
+    Ok((table, data_start))
+}
+
+/// Checks that the data of every entry lies within the `data_len` bytes of the data blob starting
+/// at `data_start` and is not shared with another entry
+fn check_entry_bounds(
+    table: &HashMap<String, DataInfo>,
+    data_start: u64,
+    data_len: u64,
+) -> Result<(), err::ParseError> {
+    let mut ranges = Vec::with_capacity(table.len());
+    for (name, info) in table {
+        let end = info.index.checked_add(info.size);
+        if end.is_none_or(|end| end > data_len) {
+            return Err(err::ParseError::OutOfBounds {
+                name: name.clone(),
+                offset: data_start.saturating_add(info.index),
+                size: info.size,
+                data_end: data_start + data_len,
+            });
+        }
+        if info.size > 0 {
+            ranges.push((info.index, info.index + info.size, name));
+        }
+    }
+    ranges.sort_unstable();
+    for pair in ranges.windows(2) {
+        let ((_, first_end, first), (second_start, _, second)) = (pair[0], pair[1]);
+        if second_start < first_end {
+            return Err(err::ParseError::OverlappingEntries {
+                first: first.clone(),
+                second: second.clone(),
+                offset: data_start + second_start,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Turns the stored bytes of an entry back into its original data, failing if it would be larger
+/// than `max_size`
+pub(crate) fn decode_entry(
+    name: &str,
+    info: &DataInfo,
+    stored: &[u8],
+    cipher: Option<&Cipher>,
+    verify_checksum: bool,
+    max_size: u64,
+) -> Result<Vec<u8>, UnpackError> {
+    if info.original_size.is_some_and(|size| size > max_size) {
+        return Err(LimitError::TotalSize(max_size).into());
+    }
+    let mut stored = stored;
+    let decrypted;
+    if let Some(cipher) = cipher {
+        decrypted = cipher.decrypt(stored, name.as_bytes()).ok_or_else(|| {
+            UnpackError::DecryptionError {
+                name: name.to_string(),
+            }
+        })?;
+        stored = &decrypted;
+    }
+    let data = info
+        .compression
+        .decompress(stored, max_size)
+        .map_err(|source| UnpackError::DecompressionError {
+            name: name.to_string(),
+            source,
+        })?
+        .ok_or(LimitError::TotalSize(max_size))?;
+    if info
+        .original_size
+        .is_some_and(|size| size != data.len() as u64)
+    {
+        let source = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "decompressed size does not match the data table",
+        );
+        return Err(UnpackError::DecompressionError {
+            name: name.to_string(),
+            source,
+        });
+    }
+    if verify_checksum && info.checksum.is_some_and(|c| c != crc32c::crc32c(&data)) {
+        return Err(UnpackError::ChecksumMismatch {
+            name: name.to_string(),
+        });
+    }
+    Ok(data)
+}
+
+#[derive(PartialEq)]
+enum ParseState {
+    String,
+    Index,
+    Size,
+    OriginalSize,
+    Codec,
+    Checksum,
+    Metadata,
+    Kind,
+}
+
+/// The bytes of a data table along with the offset of the next one
+struct TableBytes<I> {
+    bytes: I,
+    offset: u64,
+}
+
+impl<I: Iterator<Item = std::io::Result<u8>>> TableBytes<I> {
+    /// Takes the next byte of the data table, which has to have one
+    fn next(&mut self) -> Result<u8, UnpackError> {
+        let offset = self.offset;
+        let b = self
+            .bytes
+            .next()
+            .ok_or(err::ParseError::Truncated { offset })??;
+        self.offset += 1;
+        Ok(b)
+    }
+    /// Reads a little endian integer of `width` bytes, `first` being its already consumed first
+    /// byte
+    fn read_int(&mut self, first: u8, width: usize) -> Result<u64, UnpackError> {
+        let mut buf = [0u8; 8];
+        buf[0] = first;
+        for b in buf.iter_mut().take(width).skip(1) {
+            *b = self.next()?;
+        }
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Reads the data table starting at `offset`, entries of versions without per entry codecs are
+/// assumed to use `compression`
+fn read_data_table<R: BufRead>(
+    reader: &mut R,
+    offset: u64,
+    version: PackageVersion,
+    compression: Compression,
+    limits: &LoadLimits,
+) -> Result<HashMap<String, DataInfo>, err::UnpackError> {
+    let width = if version.has_wide_table() { 8 } else { 4 };
+    let mut map = HashMap::new();
+    let mut bytes = TableBytes {
+        bytes: reader.bytes(),
+        offset,
+    };
+
+    let mut state = ParseState::String;
+
+    let mut str = String::default();
+    let mut info = DataInfo::new(0, 0, compression);
+    let mut entry_offset = offset;
+    // names the entry a malformed field belongs to
+    let invalid_entry = |name: &str, offset, source| err::ParseError::InvalidEntry {
+        name: name.to_string(),
+        offset,
+        source: Box::new(source),
+    };
+
+    loop {
+        let b = bytes.next()?;
+        if b == b'\0' && state == ParseState::String {
+            break;
+        }
+        if state == ParseState::String {
+            entry_offset = bytes.offset - 1;
+            let mut str_buf = vec![b];
+            loop {
+                let str_byte = bytes.next()?;
+                if str_byte == b'\0' {
+                    break;
+                }
+                if str_buf.len() == limits.max_name_len {
+                    return Err(LimitError::NameLength(limits.max_name_len).into());
+                }
+                str_buf.push(str_byte);
+            }
+            if map.len() == limits.max_entries {
+                return Err(LimitError::Entries(limits.max_entries).into());
+            }
+            state = ParseState::Index;
+            str = String::from_utf8(str_buf).map_err(|source| UnpackError::StringError {
+                offset: entry_offset,
+                source,
+            })?;
+            if validate_name(&str).is_err() {
+                return Err(UnpackError::UnsafePath { name: str });
+            }
+            info = DataInfo::new(0, 0, compression);
+        } else if state == ParseState::Index {
+            info.index = bytes.read_int(b, width)?;
+            state = ParseState::Size;
+        } else if state == ParseState::Size {
+            info.size = bytes.read_int(b, width)?;
+            state = ParseState::OriginalSize;
+        } else if state == ParseState::OriginalSize {
+            info.original_size = Some(bytes.read_int(b, 8)?);
+            state = ParseState::Codec;
+        } else if state == ParseState::Codec {
+            let second = bytes.next()?;
+            info.compression = Compression::try_from(&[b, second][..])
+                .map_err(|e| invalid_entry(&str, entry_offset, e))?;
+            state = ParseState::Checksum;
+        } else if state == ParseState::Checksum {
+            let checksum = bytes.read_int(b, 4)?;
+            info.checksum = Some(checksum as u32);
+            state = ParseState::Metadata;
+        } else if state == ParseState::Metadata {
+            info.metadata = match b {
+                0 => None,
+                1 => {
+                    let mut buf = [0u8; EntryMetadata::STORED_LEN];
+                    for m in buf.iter_mut() {
+                        *m = bytes.next()?;
+                    }
+                    Some(EntryMetadata::from_bytes(&buf))
+                }
+                marker => {
+                    let e = err::ParseError::Metadata { marker };
+                    return Err(invalid_entry(&str, entry_offset, e).into());
+                }
+            };
+            state = ParseState::Kind;
+        } else if state == ParseState::Kind {
+            info.kind = EntryKind::try_from(b).map_err(|e| invalid_entry(&str, entry_offset, e))?;
+            state = ParseState::String;
+        }
+        // skip the fields this version does not have
+        if state == ParseState::OriginalSize && !version.has_entry_codecs() {
+            state = ParseState::String;
+        }
+        if state == ParseState::Checksum && !version.has_checksums() {
+            state = ParseState::String;
+        }
+        if state == ParseState::Metadata && !version.has_metadata() {
+            state = ParseState::String;
+        }
+        if state == ParseState::Kind && !version.has_entry_kinds() {
+            state = ParseState::String;
+        }
+        if state == ParseState::String && map.insert(str.clone(), info.clone()).is_some() {
+            let offset = entry_offset;
+            return Err(err::ParseError::DuplicateName { name: str, offset }.into());
+        }
+    }
+    Ok(map)
+}
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Receives the progress of packing, writing or unpacking a package. The methods are called on
+/// the thread running the operation, one entry after another
+pub trait ProgressSink {
+    /// The number of entries and the size of their data, once it is known and before the first
+    /// entry is started
+    fn total(&mut self, _entries: usize, _bytes: u64) {}
+    fn entry_started(&mut self, _name: &str) {}
+    /// Another `bytes` bytes of the data of the current entry were processed
+    fn bytes_processed(&mut self, _bytes: u64) {}
+    fn entry_finished(&mut self, _name: &str) {}
+}
+
+/// A [`ProgressSink`] ignoring the progress
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}
+
+/// Reports an entry that was processed at once
+pub(crate) fn report_entry(progress: &mut dyn ProgressSink, name: &str, bytes: u64) {
+    progress.entry_started(name);
+    progress.bytes_processed(bytes);
+    progress.entry_finished(name);
+}
+
+/// Cancels an operation from another thread. Operations check the token between entries and fail
+/// with a `Cancelled` error once it is cancelled, clones of a token are cancelled together
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
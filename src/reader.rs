@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::encryption::Cipher;
+use crate::entry::{ReadSeek, Window};
+use crate::err::UnpackError;
+use crate::package::DataInfo;
+use crate::parse::{decode_entry, read_header, read_package_table};
+use crate::{Compression, EntryKind, EntryMetadata, EntryReader, LoadOptions, PackageVersion};
+
+/// A package that reads the data of its entries on demand instead of loading the whole file
+/// into memory
+pub struct PackageReader<R = BufReader<fs::File>> {
+    reader: Mutex<R>,
+    /// Position of the data blob inside of the reader
+    data_start: u64,
+    table: HashMap<String, DataInfo>,
+    version: PackageVersion,
+    compression: Compression,
+    cipher: Option<Cipher>,
+    verify_checksums: bool,
+    /// Largest size an entry may decompress to
+    max_entry_size: u64,
+}
+
+impl PackageReader {
+    /// Opens a package file, reading only its header and data table
+    pub fn open(path: PathBuf) -> Result<Self, UnpackError> {
+        Self::open_with(path, &LoadOptions::default())
+    }
+    pub fn open_with(path: PathBuf, options: &LoadOptions) -> Result<Self, UnpackError> {
+        let file = fs::File::open(&path).map_err(UnpackError::file(&path))?;
+        Self::new(BufReader::new(file), options)
+    }
+}
+
+impl<R: BufRead + Seek> PackageReader<R> {
+    /// Reads the header and data table of a package from the current position of `reader`
+    pub fn new(mut reader: R, options: &LoadOptions) -> Result<Self, UnpackError> {
+        let header = read_header(&mut reader)?;
+        let cipher = header.cipher(options.key.as_ref(), &options.limits)?;
+        let (table, data_start) =
+            read_package_table(&mut reader, &header, cipher.as_ref(), &options.limits)?;
+        Ok(Self {
+            reader: Mutex::new(reader),
+            data_start,
+            table,
+            version: header.version,
+            compression: header.compression,
+            cipher,
+            verify_checksums: options.verify_checksums,
+            max_entry_size: options.limits.max_total_size,
+        })
+    }
+    pub fn has(&self, name: &str) -> bool {
+        self.table.contains_key(name)
+    }
+    /// Reads the data of an entry, returns `None` if there is no such entry or it could not be
+    /// read
+    pub fn get_data(&self, name: &str) -> Option<Vec<u8>> {
+        self.try_get_data(name).ok().flatten()
+    }
+    /// Reads the data of an entry, returns `Ok(None)` if there is no such entry
+    pub fn try_get_data(&self, name: &str) -> Result<Option<Vec<u8>>, UnpackError> {
+        match self.file(name) {
+            Some(info) => Ok(Some(self.read_entry(name, info)?)),
+            None => Ok(None),
+        }
+    }
+    /// Reads the target of a symlink entry, returns `Ok(None)` if there is no such symlink
+    pub fn link_target(&self, name: &str) -> Result<Option<String>, UnpackError> {
+        let Some(info) = self
+            .table
+            .get(name)
+            .filter(|i| i.kind == EntryKind::Symlink)
+        else {
+            return Ok(None);
+        };
+        let target = self.read_entry(name, info)?;
+        let target = String::from_utf8(target).map_err(|source| UnpackError::LinkTargetError {
+            name: name.to_string(),
+            source,
+        })?;
+        Ok(Some(target))
+    }
+    pub fn kind(&self, name: &str) -> Option<EntryKind> {
+        Some(self.table.get(name)?.kind)
+    }
+    /// The table record of a regular file entry
+    fn file(&self, name: &str) -> Option<&DataInfo> {
+        self.table.get(name).filter(|i| i.kind == EntryKind::File)
+    }
+    fn read_entry(&self, name: &str, info: &DataInfo) -> Result<Vec<u8>, UnpackError> {
+        let mut stored = vec![0u8; info.size as usize];
+        {
+            let mut reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+            reader.seek(SeekFrom::Start(self.data_start + info.index))?;
+            reader.read_exact(&mut stored)?;
+        }
+        let data = decode_entry(
+            name,
+            info,
+            &stored,
+            self.cipher.as_ref(),
+            self.verify_checksums,
+            self.max_entry_size,
+        )?;
+        Ok(data)
+    }
+    /// Opens a stream over the data of an entry, returns `Ok(None)` if there is no such entry.
+    /// Entries are read from the package as the stream is read, except for entries that are
+    /// encrypted, LZ4 compressed or checked against their checksum, which are loaded up front
+    pub fn open_entry(&self, name: &str) -> Result<Option<EntryReader<'_>>, UnpackError> {
+        let Some(info) = self.file(name) else {
+            return Ok(None);
+        };
+        if self.cipher.is_some() || self.verify_checksums || !info.compression.can_stream() {
+            return Ok(self.try_get_data(name)?.map(EntryReader::from_memory));
+        }
+        let reader: &Mutex<dyn ReadSeek + '_> = &self.reader;
+        let start = self.data_start + info.index;
+        let size = info.size;
+        let entry = EntryReader::decoding(info.compression, info.original_size, move || {
+            Window::new(reader, start, size)
+        })?;
+        Ok(Some(entry))
+    }
+    /// Returns the file metadata stored with an entry, if it has any
+    pub fn metadata(&self, name: &str) -> Option<EntryMetadata> {
+        self.table.get(name)?.metadata
+    }
+    /// The names of all entries, in no particular order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.table.keys().map(|k| k.as_str())
+    }
+    pub fn version(&self) -> PackageVersion {
+        self.version
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
@@ -0,0 +1,58 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, SIGNATURE_LENGTH};
+
+use crate::err::UnpackError;
+use crate::parse::{read_header, FOOTER_MAGIC};
+
+/// Marks the end of a signed package, preceded by the signature of everything before it
+pub(crate) const SIGNATURE_MAGIC: [u8; 4] = [0x4D, 0x33, 0x53, 0x47];
+pub(crate) const TRAILER_LEN: usize = SIGNATURE_LENGTH + SIGNATURE_MAGIC.len();
+
+/// Appends a signature trailer covering all of `buf`
+pub(crate) fn sign(buf: &mut Vec<u8>, key: &SigningKey) {
+    let signature = key.sign(buf);
+    buf.extend_from_slice(&signature.to_bytes());
+    buf.extend_from_slice(&SIGNATURE_MAGIC);
+}
+
+/// Splits a signed file into its signed part and the signature, returns `None` if the file has
+/// no signature trailer
+pub(crate) fn split(file: &[u8]) -> Option<(&[u8], Signature)> {
+    let trailer_start = file.len().checked_sub(TRAILER_LEN)?;
+    let (unsigned, trailer) = file.split_at(trailer_start);
+    let (signature, magic) = trailer.split_at(SIGNATURE_LENGTH);
+    if magic != SIGNATURE_MAGIC {
+        return None;
+    }
+    let signature = Signature::from_slice(signature).ok()?;
+    Some((unsigned, signature))
+}
+
+/// The length of the part of a package file a new signature covers, leaving out the signature
+/// trailer it may already have. Packages with a leading data table can end in data that looks
+/// like a trailer, so one is only recognized behind a footer or when `key` made it
+pub(crate) fn unsigned_len(file: &[u8], key: &VerifyingKey) -> usize {
+    let Some((unsigned, signature)) = split(file) else {
+        return file.len();
+    };
+    let behind_footer = read_header(&mut &file[..])
+        .is_ok_and(|header| header.version.has_trailing_table())
+        && unsigned.ends_with(&FOOTER_MAGIC);
+    if behind_footer || key.verify_strict(unsigned, &signature).is_ok() {
+        unsigned.len()
+    } else {
+        file.len()
+    }
+}
+
+/// Checks that the file is signed by one of the `keys` and returns the length of its signed part
+pub(crate) fn verify(file: &[u8], keys: &[VerifyingKey]) -> Result<usize, UnpackError> {
+    let (unsigned, signature) = split(file).ok_or(UnpackError::BadSignature)?;
+    if keys
+        .iter()
+        .any(|key| key.verify_strict(unsigned, &signature).is_ok())
+    {
+        Ok(unsigned.len())
+    } else {
+        Err(UnpackError::BadSignature)
+    }
+}
@@ -86,7 +86,7 @@ lazy_static! {
 }
 
 #[test]
-fn test_packing() -> Result<(), super::err::PackingError> {
+fn test_packing() -> Result<(), Box<dyn Error>> {
     let tmp = create_test_directory(&PACKING_TEST_MODEL)?;
     let pack = super::package_dir(tmp.path().to_path_buf())?;
     //let tmp_path = tmp.path().to_path_buf();
@@ -118,7 +118,7 @@ fn test_packing() -> Result<(), super::err::PackingError> {
     Ok(())
 }
 #[test]
-fn test_packing_and_unpacking() -> Result<(), super::err::PackingError> {
+fn test_packing_and_unpacking() -> Result<(), Box<dyn Error>> {
     let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
     let pack = super::package_dir(src_tmp.path().to_path_buf())?;
     let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
@@ -152,7 +152,7 @@ fn test_packing_and_unpacking() -> Result<(), super::err::PackingError> {
     Ok(())
 }
 #[test]
-fn test_pack_and_save() -> Result<(), super::err::PackingError> {
+fn test_pack_and_save() -> Result<(), Box<dyn Error>> {
     let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
     let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
     let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
@@ -219,3 +219,1747 @@ fn test_remove() -> Result<(), Box<dyn Error>> {
     drop(dest_tmp);
     Ok(())
 }
+#[test]
+fn test_large_entries() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    // both the entry and the offsets behind it exceed the old 24-bit limit
+    let large_files = ["large_a.bin", "large_b.bin"];
+    for (i, name) in large_files.iter().enumerate() {
+        let mut data = vec![i as u8; 17 * 1024 * 1024];
+        data[16 * 1024 * 1024] = 0xFF;
+        pack.insert_data(name.to_string(), data)?;
+    }
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    let loaded = super::load_package(out_file)?;
+    assert_eq!(loaded.version().ver, super::CURRENT_VERSION);
+    for name in large_files {
+        assert_eq!(loaded.get_data_ref(name), pack.get_data_ref(name));
+    }
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_entry_too_large() -> Result<(), Box<dyn Error>> {
+    use super::err::WriteError;
+    use super::writer::write_table_entry;
+    let info = |stored_size| super::EntryInfo {
+        compression: super::Compression::None,
+        stored_size,
+        original_size: stored_size,
+        checksum: None,
+        kind: super::EntryKind::File,
+    };
+    let old = super::PackageVersion::from((0, 0, 0, 2));
+    let beyond = u32::MAX as u64 + 1;
+    let mut table = vec![];
+    write_table_entry(
+        &mut table,
+        "fits",
+        old,
+        u32::MAX as u64,
+        &info(1),
+        None,
+        super::EntryKind::File,
+    )?;
+    let written = table.clone();
+
+    // offsets and sizes that do not fit into 32 bits fail instead of being truncated
+    for (index, size) in [(beyond, 1), (0, beyond)] {
+        let res = write_table_entry(
+            &mut table,
+            "large",
+            old,
+            index,
+            &info(size),
+            None,
+            super::EntryKind::File,
+        );
+        assert!(matches!(
+            res,
+            Err(WriteError::EntryTooLarge { name, version }) if name == "large" && version == old
+        ));
+        assert_eq!(table, written);
+    }
+    let wide = super::PackageVersion::from((0, 0, 1, 0));
+    write_table_entry(
+        &mut table,
+        "large",
+        wide,
+        beyond,
+        &info(beyond),
+        None,
+        super::EntryKind::File,
+    )?;
+    Ok(())
+}
+#[test]
+fn test_old_version() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    pack.set_version(super::PackageVersion::from((0, 0, 0, 2)));
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.version().ver, (0, 0, 0, 2));
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+
+    pack.set_version(super::PackageVersion::from((0, 1, 0, 0)));
+    let res = super::write_package(out_file, &mut pack);
+    assert!(matches!(
+        res,
+        Err(super::err::WriteError::UnsupportedFormat(_))
+    ));
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_zstd_compression() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let text = "{\"key\": \"value\"}\n".repeat(1024);
+    pack.insert_data("directory/data.json".to_string(), text.clone().into_bytes())?;
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+
+    let mut plain_file = dest_tmp.path().join("plain");
+    super::write_package(plain_file.clone(), &mut pack)?;
+    plain_file.set_extension("m3pkg");
+
+    pack.set_compression(super::Compression::Zstd);
+    pack.set_compression_level(19);
+    let mut zstd_file = dest_tmp.path().join("zstd");
+    super::write_package(zstd_file.clone(), &mut pack)?;
+    zstd_file.set_extension("m3pkg");
+    assert!(std::fs::metadata(&zstd_file)?.len() < std::fs::metadata(&plain_file)?.len());
+
+    let loaded = super::load_package(zstd_file)?;
+    assert_eq!(loaded.compression(), super::Compression::Zstd);
+    assert_eq!(
+        loaded.get_data("directory/data.json"),
+        Some(text.into_bytes())
+    );
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    assert_eq!(
+        loaded.get_data_ref("directory/text_file.txt"),
+        Some(&[][..])
+    );
+
+    // versions from before compression support cannot hold compressed entries
+    pack.set_version(super::PackageVersion::from((0, 0, 0, 2)));
+    let res = super::write_package(dest_tmp.path().join("old"), &mut pack);
+    assert!(matches!(
+        res,
+        Err(super::err::WriteError::UnsupportedFormat(
+            super::err::UnsupportedError::Compression
+        ))
+    ));
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+fn compression_round_trip(
+    compression: super::Compression,
+    level: i32,
+) -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let text = "<p>lorem ipsum dolor sit amet</p>\n".repeat(512);
+    let mut binary = [0u8; 256];
+    rand::fill(&mut binary);
+    pack.insert_data(
+        "directory/index.html".to_string(),
+        text.clone().into_bytes(),
+    )?;
+    pack.insert_data("directory/random.bin".to_string(), binary.to_vec())?;
+    pack.set_compression(compression);
+    pack.set_compression_level(level);
+
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    assert!(std::fs::metadata(&out_file)?.len() < text.len() as u64);
+
+    let loaded = super::load_package(out_file)?;
+    assert_eq!(loaded.compression(), compression);
+    assert_eq!(
+        loaded.get_data_ref("directory/index.html"),
+        Some(text.as_bytes())
+    );
+    assert_eq!(
+        loaded.get_data_ref("directory/random.bin"),
+        Some(&binary[..])
+    );
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    assert_eq!(
+        loaded.get_data_ref("directory/text_file.txt"),
+        Some(&[][..])
+    );
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_deflate_compression() -> Result<(), Box<dyn Error>> {
+    compression_round_trip(super::Compression::Deflate, 0)?;
+    compression_round_trip(super::Compression::Deflate, 9)
+}
+#[test]
+fn test_lz4_compression() -> Result<(), Box<dyn Error>> {
+    compression_round_trip(super::Compression::Lz4, 0)
+}
+#[test]
+fn test_entry_codecs() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let text = "{\"key\": \"value\"}\n".repeat(256);
+    let mut binary = [0u8; 1024];
+    rand::fill(&mut binary);
+    pack.insert_data("data.json".to_string(), text.clone().into_bytes())?;
+    pack.insert_data("random.bin".to_string(), binary.to_vec())?;
+    pack.set_compression(super::Compression::Zstd);
+
+    let info = pack.entry_info("data.json").unwrap();
+    assert_eq!(info.compression, super::Compression::None);
+    assert_eq!(info.stored_size, text.len() as u64);
+
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    let loaded = super::load_package(out_file.clone())?;
+    for p in [&pack, &loaded] {
+        let text_info = p.entry_info("data.json").unwrap();
+        assert_eq!(text_info.compression, super::Compression::Zstd);
+        assert_eq!(text_info.original_size, text.len() as u64);
+        assert!(text_info.stored_size < text_info.original_size);
+        // random data does not compress, so it has to be stored as is
+        let binary_info = p.entry_info("random.bin").unwrap();
+        assert_eq!(binary_info.compression, super::Compression::None);
+        assert_eq!(binary_info.stored_size, binary.len() as u64);
+        assert_eq!(binary_info.original_size, binary.len() as u64);
+    }
+    assert_eq!(loaded.get_data_ref("random.bin"), Some(&binary[..]));
+    assert_eq!(loaded.get_data_ref("data.json"), Some(text.as_bytes()));
+    assert!(loaded.entry_info("missing.bin").is_none());
+
+    // the previous version can only store every entry with the package codec
+    pack.set_version(super::PackageVersion::from((0, 0, 1, 0)));
+    super::write_package(out_file.clone(), &mut pack)?;
+    let loaded = super::load_package(out_file)?;
+    let binary_info = loaded.entry_info("random.bin").unwrap();
+    assert_eq!(binary_info.compression, super::Compression::Zstd);
+    assert_eq!(binary_info.original_size, binary.len() as u64);
+    assert_eq!(loaded.get_data_ref("random.bin"), Some(&binary[..]));
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_checksums() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let corrupted_file = "directory/corrupted.txt";
+    let content = b"these bytes are going to be corrupted";
+    pack.insert_data(corrupted_file.to_string(), content.to_vec())?;
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    assert!(pack.entry_info(corrupted_file).unwrap().checksum.is_some());
+
+    let loaded = super::load_package_with(out_file.clone(), &super::LoadOptions::strict())?;
+    assert!(loaded.verify().is_empty());
+
+    let mut bytes = std::fs::read(&out_file)?;
+    let pos = bytes
+        .windows(content.len())
+        .position(|w| w == content)
+        .expect("entry data not found in the package file");
+    bytes[pos] ^= 0xFF;
+    std::fs::write(&out_file, bytes)?;
+
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.verify(), vec![corrupted_file.to_string()]);
+    let res = super::load_package_with(out_file, &super::LoadOptions::strict());
+    assert!(matches!(
+        res,
+        Err(super::err::UnpackError::ChecksumMismatch { name }) if name == corrupted_file
+    ));
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_signing() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let mut secret = [0u8; 32];
+    rand::fill(&mut secret);
+    let key = super::SigningKey::from_bytes(&secret);
+    rand::fill(&mut secret);
+    let other_key = super::SigningKey::from_bytes(&secret);
+    let trusted = [other_key.verifying_key(), key.verifying_key()];
+
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut signed_file = dest_tmp.path().join("signed");
+    super::write_package_signed(signed_file.clone(), &mut pack, &key)?;
+    signed_file.set_extension("m3pkg");
+    let loaded = super::load_package_verified(signed_file.clone(), &trusted)?;
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    // the trailer does not get in the way of loading without verification
+    assert!(super::load_package(signed_file.clone()).is_ok());
+    let res = super::load_package_verified(signed_file.clone(), &[other_key.verifying_key()]);
+    assert!(matches!(res, Err(super::err::UnpackError::BadSignature)));
+
+    let mut bytes = std::fs::read(&signed_file)?;
+    let pos = bytes
+        .windows(4)
+        .position(|w| w == b"text")
+        .expect("entry data not found in the package file");
+    bytes[pos] = b'T';
+    std::fs::write(&signed_file, bytes)?;
+    let res = super::load_package_verified(signed_file, &trusted);
+    assert!(matches!(res, Err(super::err::UnpackError::BadSignature)));
+
+    let mut unsigned_file = dest_tmp.path().join("unsigned");
+    super::write_package(unsigned_file.clone(), &mut pack)?;
+    unsigned_file.set_extension("m3pkg");
+    let res = super::load_package_verified(unsigned_file.clone(), &trusted);
+    assert!(matches!(res, Err(super::err::UnpackError::BadSignature)));
+    super::sign_package(unsigned_file.clone(), &other_key)?;
+    super::sign_package(unsigned_file.clone(), &key)?;
+    let res = super::load_package_verified(unsigned_file.clone(), &[other_key.verifying_key()]);
+    assert!(matches!(res, Err(super::err::UnpackError::BadSignature)));
+    assert!(super::load_package_verified(unsigned_file, &trusted).is_ok());
+
+    // data at the end of a package with a leading table is not mistaken for a signature
+    let mut data = vec![0x5A; 96];
+    data.extend_from_slice(&super::signature::SIGNATURE_MAGIC);
+    let legacy_file = dest_tmp.path().join("legacy.m3pkg");
+    std::fs::write(&legacy_file, raw_package(&[("tail", 0, 100)], &data))?;
+    super::sign_package(legacy_file.clone(), &key)?;
+    super::sign_package(legacy_file.clone(), &key)?;
+    let loaded = super::load_package_verified(legacy_file.clone(), &trusted)?;
+    assert_eq!(loaded.get_data_ref("tail"), Some(data.as_slice()));
+    let signed_len = std::fs::metadata(&legacy_file)?.len();
+    assert_eq!(
+        signed_len,
+        raw_package(&[("tail", 0, 100)], &data).len() as u64 + 68
+    );
+
+    let missing = dest_tmp.path().join("missing.m3pkg");
+    let res = super::sign_package(missing.clone(), &key);
+    assert!(matches!(res, Err(super::err::WriteError::ReadError { path, .. }) if path == missing));
+
+    // encrypted packages are verified before they are decrypted with the key
+    let passphrase = super::EncryptionKey::Passphrase("signed".to_string());
+    pack.set_encryption(Some(super::Encryption {
+        key: passphrase.clone(),
+        encrypt_table: true,
+    }));
+    let mut encrypted_file = dest_tmp.path().join("encrypted");
+    super::write_package_signed(encrypted_file.clone(), &mut pack, &key)?;
+    encrypted_file.set_extension("m3pkg");
+    let res = super::load_package_verified(encrypted_file.clone(), &trusted);
+    assert!(matches!(res, Err(super::err::UnpackError::KeyRequired)));
+    let options = super::LoadOptions {
+        key: Some(passphrase),
+        ..Default::default()
+    };
+    let loaded = super::load_package_verified_with(encrypted_file, &trusted, &options)?;
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+#[test]
+fn test_encryption() -> Result<(), Box<dyn Error>> {
+    use super::err::{EncryptionError, UnpackError};
+    use super::{Encryption, EncryptionKey};
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let secret = "licensed secret contents".repeat(16);
+    pack.insert_data(
+        "directory/secret.txt".to_string(),
+        secret.clone().into_bytes(),
+    )?;
+    pack.set_compression(super::Compression::Lz4);
+    let mut raw = [0u8; 32];
+    rand::fill(&mut raw);
+    let key = EncryptionKey::Raw(raw);
+    let passphrase = EncryptionKey::Passphrase("correct horse battery staple".to_string());
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+
+    for (key, encrypt_table) in [(&key, false), (&key, true), (&passphrase, true)] {
+        pack.set_encryption(Some(Encryption {
+            key: key.clone(),
+            encrypt_table,
+        }));
+        let mut out_file = dest_tmp.path().join("pack");
+        super::write_package(out_file.clone(), &mut pack)?;
+        out_file.set_extension("m3pkg");
+
+        let bytes = std::fs::read(&out_file)?;
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"licensed secret"));
+        assert_eq!(contains(b"directory/secret.txt"), !encrypt_table);
+
+        let loaded = super::load_package_encrypted(out_file.clone(), key)?;
+        assert_eq!(
+            loaded.get_data_ref("directory/secret.txt"),
+            Some(secret.as_bytes())
+        );
+        assert_eq!(
+            loaded.get_data_ref("text_file.txt"),
+            Some("text".as_bytes())
+        );
+        assert!(loaded
+            .encryption()
+            .is_some_and(|e| e.encrypt_table == encrypt_table));
+
+        let res = super::load_package(out_file.clone());
+        assert!(matches!(res, Err(UnpackError::KeyRequired)));
+    }
+
+    let mut out_file = dest_tmp.path().join("pack");
+    for encrypt_table in [false, true] {
+        pack.set_encryption(Some(Encryption {
+            key: key.clone(),
+            encrypt_table,
+        }));
+        super::write_package(out_file.clone(), &mut pack)?;
+        out_file.set_extension("m3pkg");
+        let mut wrong = raw;
+        wrong[0] ^= 0xFF;
+        let res = super::load_package_encrypted(out_file.clone(), &EncryptionKey::Raw(wrong));
+        match encrypt_table {
+            true => assert!(matches!(res, Err(UnpackError::TableDecryptionError))),
+            false => assert!(matches!(res, Err(UnpackError::DecryptionError { .. }))),
+        }
+        let res = super::load_package_encrypted(out_file.clone(), &passphrase);
+        assert!(matches!(
+            res,
+            Err(UnpackError::EncryptionError(
+                EncryptionError::RawKeyRequired
+            ))
+        ));
+    }
+    drop(src_tmp);
+    drop(dest_tmp);
+    Ok(())
+}
+
+#[test]
+fn test_package_reader() -> Result<(), Box<dyn Error>> {
+    use super::{Encryption, EncryptionKey, LoadOptions, PackageReader};
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    pack.insert_data("large.txt".to_string(), "large".repeat(1000).into_bytes())?;
+    pack.set_compression(super::Compression::Zstd);
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+
+    let reader = PackageReader::open(out_file.clone())?;
+    assert_eq!(reader.version(), pack.version());
+    assert_eq!(reader.compression(), super::Compression::Zstd);
+    assert_eq!(
+        reader.names().count(),
+        pack.get_files().len() + pack.directories().len()
+    );
+    // read in reverse to make sure entries are not read sequentially
+    let mut names = reader.names().map(str::to_string).collect::<Vec<_>>();
+    names.sort();
+    for name in names.iter().rev() {
+        assert!(reader.has(name));
+        assert_eq!(reader.get_data(name).as_deref(), pack.get_data_ref(name));
+    }
+    assert!(!reader.has("missing.txt"));
+    assert_eq!(reader.get_data("missing.txt"), None);
+
+    let key = EncryptionKey::Passphrase("reader".to_string());
+    pack.set_encryption(Some(Encryption {
+        key: key.clone(),
+        encrypt_table: true,
+    }));
+    super::write_package(out_file.clone(), &mut pack)?;
+    assert!(PackageReader::open(out_file.clone()).is_err());
+    let options = LoadOptions {
+        key: Some(key),
+        ..LoadOptions::strict()
+    };
+    let reader = PackageReader::open_with(out_file, &options)?;
+    assert_eq!(
+        reader.try_get_data("large.txt")?.as_deref(),
+        pack.get_data_ref("large.txt")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_mapped_package() -> Result<(), Box<dyn Error>> {
+    use super::MappedPackage;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    pack.insert_data("large.txt".to_string(), "large".repeat(1000).into_bytes())?;
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+
+    let mapped = MappedPackage::open(out_file.clone())?;
+    assert_eq!(
+        mapped.names().count(),
+        pack.get_files().len() + pack.directories().len()
+    );
+    for name in pack.get_files().keys() {
+        assert!(mapped.has(name));
+        assert_eq!(mapped.get_data_ref(name), pack.get_data_ref(name));
+        assert!(matches!(
+            mapped.get_data(name),
+            Some(std::borrow::Cow::Borrowed(_))
+        ));
+    }
+    assert_eq!(mapped.get_data_ref("missing.txt"), None);
+
+    // compressed entries can not be borrowed but are still readable
+    pack.set_compression(super::Compression::Zstd);
+    super::write_package(out_file.clone(), &mut pack)?;
+    let mapped = MappedPackage::open(out_file)?;
+    assert_eq!(mapped.get_data_ref("large.txt"), None);
+    assert_eq!(
+        mapped.get_data("large.txt").as_deref(),
+        pack.get_data_ref("large.txt")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_package_writer() -> Result<(), Box<dyn Error>> {
+    use super::err::WriteError;
+    use super::{Compression, Encryption, EncryptionKey, PackageWriter};
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let large = "streamed".repeat(20_000);
+    let key = EncryptionKey::Passphrase("writer".to_string());
+
+    for (compression, encryption) in [
+        (Compression::None, None),
+        (Compression::Lz4, None),
+        (
+            Compression::Zstd,
+            Some(Encryption {
+                key: key.clone(),
+                encrypt_table: true,
+            }),
+        ),
+    ] {
+        let mut out_file = dest_tmp.path().join("pack");
+        let mut writer = PackageWriter::create(out_file.clone(), compression, encryption.clone())?;
+        writer.add_dir(src_tmp.path().to_path_buf())?;
+        writer.add_file("streamed/large.txt", large.as_bytes())?;
+        let res = writer.add_file("text_file.txt", "again".as_bytes());
+        assert!(matches!(res, Err(WriteError::DuplicateEntry { .. })));
+        let res = writer.add_file("../escape.txt", "escape".as_bytes());
+        assert!(matches!(res, Err(WriteError::InvalidName(_))));
+        writer.finish()?;
+        out_file.set_extension("m3pkg");
+
+        let options = super::LoadOptions {
+            key: encryption.map(|e| e.key),
+            ..super::LoadOptions::strict()
+        };
+        let loaded = super::load_package_with(out_file.clone(), &options)?;
+        assert_eq!(loaded.version().ver, super::CURRENT_VERSION);
+        assert_eq!(loaded.compression(), compression);
+        assert_eq!(loaded.get_files().len(), pack.get_files().len() + 1);
+        for (name, data) in pack.get_files() {
+            assert_eq!(loaded.get_data_ref(name), Some(data.as_slice()));
+        }
+        assert_eq!(
+            loaded.get_data_ref("streamed/large.txt"),
+            Some(large.as_bytes())
+        );
+        assert!(loaded.verify().is_empty());
+
+        // the trailing table is still found behind a signature and by the lazy readers
+        let signing_key = super::SigningKey::from_bytes(&[7u8; 32]);
+        super::sign_package(out_file.clone(), &signing_key)?;
+        let reader = super::PackageReader::open_with(out_file.clone(), &options)?;
+        assert_eq!(
+            reader.get_data("streamed/large.txt").as_deref(),
+            Some(large.as_bytes())
+        );
+        let mapped = super::MappedPackage::open_with(out_file, &options)?;
+        assert_eq!(
+            mapped.get_data("text_file.txt").as_deref(),
+            Some("text".as_bytes())
+        );
+    }
+
+    // dots inside of names are packaged like any other name
+    let dotted_tmp = tempdir::TempDir::new("dotted_tmp")?;
+    std::fs::create_dir(dotted_tmp.path().join("a..b"))?;
+    std::fs::write(dotted_tmp.path().join("a..b/v1..2.txt"), "dotted")?;
+    let out_file = dest_tmp.path().join("dotted.m3pkg");
+    let mut writer = PackageWriter::create(out_file.clone(), Compression::None, None)?;
+    writer.add_dir(dotted_tmp.path().to_path_buf())?;
+    writer.finish()?;
+    let loaded = super::load_package(out_file)?;
+    assert_eq!(
+        loaded.get_data_ref("a..b/v1..2.txt"),
+        Some("dotted".as_bytes())
+    );
+    Ok(())
+}
+
+/// Reads `entry` in a few different orders, comparing it against `expected`
+fn check_entry_reader(mut entry: super::EntryReader, expected: &[u8]) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    let mut buf = vec![];
+    entry.read_to_end(&mut buf)?;
+    assert_eq!(buf, expected);
+
+    let mid = expected.len() / 2;
+    assert_eq!(entry.seek(SeekFrom::Start(mid as u64))?, mid as u64);
+    let mut chunk = [0u8; 16];
+    entry.read_exact(&mut chunk)?;
+    assert_eq!(chunk, expected[mid..mid + 16]);
+    entry.seek(SeekFrom::Current(-32))?;
+    entry.read_exact(&mut chunk)?;
+    assert_eq!(chunk, expected[mid - 16..mid]);
+    entry.seek(SeekFrom::End(-16))?;
+    entry.read_exact(&mut chunk)?;
+    assert_eq!(chunk, expected[expected.len() - 16..]);
+    assert_eq!(entry.read(&mut chunk)?, 0);
+    assert!(entry
+        .seek(SeekFrom::Current(-(expected.len() as i64) - 1))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_entry_reader() -> Result<(), Box<dyn Error>> {
+    use super::{Compression, MappedPackage, PackageReader};
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let data = (0..50_000u32)
+        .flat_map(|i| (i % 251).to_le_bytes())
+        .collect::<Vec<_>>();
+    pack.insert_data("audio/track.raw".to_string(), data.clone())?;
+    check_entry_reader(pack.open("audio/track.raw").unwrap(), &data)?;
+    assert!(pack.open("missing").is_none());
+
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    for compression in [
+        Compression::None,
+        Compression::Zstd,
+        Compression::Deflate,
+        Compression::Lz4,
+    ] {
+        pack.set_compression(compression);
+        let mut out_file = dest_tmp.path().join("pack");
+        super::write_package(out_file.clone(), &mut pack)?;
+        out_file.set_extension("m3pkg");
+
+        let reader = PackageReader::open(out_file.clone())?;
+        check_entry_reader(reader.open_entry("audio/track.raw")?.unwrap(), &data)?;
+        // streams of the same reader do not disturb each other
+        let mut first = reader.open_entry("audio/track.raw")?.unwrap();
+        let mut second = reader.open_entry("text_file.txt")?.unwrap();
+        let mut buf = [0u8; 4];
+        first.read_exact(&mut buf)?;
+        second.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"text");
+        first.read_exact(&mut buf)?;
+        assert_eq!(buf, data[4..8]);
+
+        let mapped = MappedPackage::open(out_file)?;
+        check_entry_reader(mapped.open_entry("audio/track.raw")?.unwrap(), &data)?;
+        assert!(mapped.open_entry("missing")?.is_none());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let script = src_tmp.path().join("directory/run.sh");
+    std::fs::write(&script, "#!/bin/sh\necho hi\n")?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o751))?;
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+    File::options()
+        .write(true)
+        .open(&script)?
+        .set_modified(mtime)?;
+
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let metadata = pack.metadata("directory/run.sh").unwrap();
+    assert_eq!(metadata.mode, 0o751);
+    assert_eq!(metadata.mtime, 1_500_000_000);
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.metadata("directory/run.sh"), Some(metadata));
+    let reader = super::PackageReader::open(out_file.clone())?;
+    assert_eq!(reader.metadata("directory/run.sh"), Some(metadata));
+
+    // metadata is only restored when asked for
+    let plain_dir = dest_tmp.path().join("plain");
+    super::unpack_to_dir(plain_dir.clone(), &loaded)?;
+    let unpacked = std::fs::metadata(plain_dir.join("directory/run.sh"))?;
+    assert_ne!(unpacked.modified()?, mtime);
+
+    let restored_dir = dest_tmp.path().join("restored");
+    let options = super::UnpackOptions {
+        restore_metadata: true,
+        ..Default::default()
+    };
+    super::unpack_to_dir_with(restored_dir.clone(), &loaded, &options)?;
+    let restored = std::fs::metadata(restored_dir.join("directory/run.sh"))?;
+    assert_eq!(restored.permissions().mode() & 0o7777, 0o751);
+    assert_eq!(restored.modified()?, mtime);
+
+    // the setuid, setgid and sticky bits are only restored when asked for explicitly
+    let setuid = src_tmp.path().join("setuid");
+    std::fs::write(&setuid, "privileged")?;
+    std::fs::set_permissions(&setuid, std::fs::Permissions::from_mode(0o4755))?;
+    let privileged = super::package_dir(src_tmp.path().to_path_buf())?;
+    assert_eq!(privileged.metadata("setuid").unwrap().mode, 0o4755);
+    let masked_dir = dest_tmp.path().join("masked");
+    super::unpack_to_dir_with(masked_dir.clone(), &privileged, &options)?;
+    let masked = std::fs::metadata(masked_dir.join("setuid"))?;
+    assert_eq!(masked.permissions().mode() & 0o7777, 0o755);
+    let options = super::UnpackOptions {
+        restore_special_bits: true,
+        ..options
+    };
+    let special_dir = dest_tmp.path().join("special");
+    super::unpack_to_dir_with(special_dir.clone(), &privileged, &options)?;
+    let special = std::fs::metadata(special_dir.join("setuid"))?;
+    assert_eq!(special.permissions().mode() & 0o7777, 0o4755);
+
+    // older versions have no room for metadata
+    pack.set_version(super::PackageVersion::from((0, 0, 5, 0)));
+    super::write_package(out_file.clone(), &mut pack)?;
+    let loaded = super::load_package(out_file)?;
+    assert_eq!(loaded.metadata("directory/run.sh"), None);
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        Some("text".as_bytes())
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() -> Result<(), Box<dyn Error>> {
+    use super::{EntryKind, PackOptions, SymlinkPolicy};
+    use std::os::unix::fs::symlink;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let outside_tmp = tempdir::TempDir::new("outside_tmp")?;
+    let outside = outside_tmp.path().join("outside.txt");
+    std::fs::write(&outside, "outside")?;
+    symlink("text_file.txt", src_tmp.path().join("link.txt"))?;
+    symlink("../directory", src_tmp.path().join("directory/loop"))?;
+    symlink("missing.txt", src_tmp.path().join("dangling.txt"))?;
+    symlink(&outside, src_tmp.path().join("outside.txt"))?;
+
+    let followed = super::package_dir(src_tmp.path().to_path_buf())?;
+    assert_eq!(followed.get_data_ref("link.txt"), Some("text".as_bytes()));
+    assert_eq!(
+        followed.get_data_ref("outside.txt"),
+        Some("outside".as_bytes())
+    );
+    assert!(!followed.has("dangling.txt"));
+    assert!(followed.links().is_empty());
+
+    let skip = PackOptions {
+        symlinks: SymlinkPolicy::Skip,
+        ..Default::default()
+    };
+    let skipped = super::package_dir_with(src_tmp.path().to_path_buf(), &skip)?;
+    assert!(!skipped.has("link.txt") && !skipped.has("outside.txt"));
+    assert_eq!(skipped.get_files().len(), followed.get_files().len() - 2);
+
+    let link = PackOptions {
+        symlinks: SymlinkPolicy::Link,
+        ..Default::default()
+    };
+    let mut linked = super::package_dir_with(src_tmp.path().to_path_buf(), &link)?;
+    assert_eq!(linked.kind("link.txt"), Some(EntryKind::Symlink));
+    assert_eq!(linked.link_target("dangling.txt"), Some("missing.txt"));
+    assert_eq!(linked.get_data_ref("link.txt"), None);
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut linked)?;
+    out_file.set_extension("m3pkg");
+
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.links(), linked.links());
+    // symlinks are in the data table like files, their data being the target
+    let info = loaded.entry_info("dangling.txt").unwrap();
+    assert_eq!(info.kind, EntryKind::Symlink);
+    assert_eq!(info.original_size, "missing.txt".len() as u64);
+    let reader = super::PackageReader::open(out_file.clone())?;
+    assert_eq!(
+        reader.link_target("directory/loop")?.as_deref(),
+        Some("../directory")
+    );
+    assert_eq!(reader.get_data("link.txt"), None);
+
+    // the absolute link escapes the destination
+    let res = super::unpack_to_dir(dest_tmp.path().join("escaping"), &loaded);
+    assert!(res.is_err());
+
+    linked.remove_data("outside.txt");
+    let unpack_dir = dest_tmp.path().join("unpacked");
+    super::unpack_to_dir(unpack_dir.clone(), &linked)?;
+    assert_eq!(
+        std::fs::read_link(unpack_dir.join("link.txt"))?,
+        PathBuf::from("text_file.txt")
+    );
+    assert_eq!(
+        std::fs::read_to_string(unpack_dir.join("link.txt"))?,
+        "text"
+    );
+
+    for target in ["../../escape", "loop/../x", "/etc/passwd"] {
+        let mut pack = super::Package::from_file_info(
+            vec![],
+            super::PackageVersion::from(super::CURRENT_VERSION),
+            super::Compression::None,
+        );
+        pack.insert_link("directory/loop".to_string(), "..".to_string())?;
+        pack.insert_link("directory/evil".to_string(), target.to_string())?;
+        let res = super::unpack_to_dir(dest_tmp.path().join("evil"), &pack);
+        assert!(res.is_err(), "`{target}` was not refused");
+    }
+
+    linked.set_version(super::PackageVersion::from((0, 0, 6, 0)));
+    let res = super::write_package(out_file, &mut linked);
+    assert!(matches!(
+        res,
+        Err(super::err::WriteError::UnsupportedFormat(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_directories() -> Result<(), Box<dyn Error>> {
+    use super::EntryKind;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    std::fs::create_dir_all(src_tmp.path().join("empty/nested"))?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    assert_eq!(pack.kind("empty/nested"), Some(EntryKind::Directory));
+    assert!(pack.directories().contains("directory"));
+    pack.insert_dir("created/".to_string())?;
+    assert!(pack.has("created"));
+    assert!(pack.insert_dir("../outside".to_string()).is_err());
+    let info = pack.entry_info("created").unwrap();
+    assert_eq!((info.kind, info.stored_size), (EntryKind::Directory, 0));
+
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    super::write_package(out_file.clone(), &mut pack)?;
+    out_file.set_extension("m3pkg");
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.directories(), pack.directories());
+    assert_eq!(
+        loaded.entry_info("empty/nested").map(|info| info.kind),
+        Some(EntryKind::Directory)
+    );
+    assert_eq!(
+        loaded.entry_info("text_file.txt").map(|info| info.kind),
+        Some(EntryKind::File)
+    );
+
+    let unpack_dir = dest_tmp.path().join("unpacked");
+    super::unpack_to_dir(unpack_dir.clone(), &loaded)?;
+    assert!(unpack_dir.join("empty/nested").is_dir());
+    assert!(unpack_dir.join("created").is_dir());
+    assert_eq!(
+        std::fs::read_to_string(unpack_dir.join("text_file.txt"))?,
+        "text"
+    );
+
+    // the streaming writer keeps them as well
+    let mut writer =
+        super::PackageWriter::create(out_file.clone(), super::Compression::None, None)?;
+    writer.add_dir(src_tmp.path().to_path_buf())?;
+    writer.finish()?;
+    let reader = super::PackageReader::open(out_file.clone())?;
+    assert_eq!(reader.kind("empty/nested"), Some(EntryKind::Directory));
+    assert_eq!(reader.get_data("empty/nested"), None);
+
+    // older versions only keep the directories that hold files
+    pack.set_version(super::PackageVersion::from((0, 0, 6, 0)));
+    super::write_package(out_file.clone(), &mut pack)?;
+    let loaded = super::load_package(out_file)?;
+    assert!(loaded.directories().is_empty());
+    assert!(loaded.has("directory/text_file.txt"));
+    Ok(())
+}
+
+#[test]
+fn test_unsafe_paths() -> Result<(), Box<dyn Error>> {
+    use super::err::UnpackError;
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    let mut out_file = dest_tmp.path().join("pack");
+    for name in [
+        "../../etc/evil",
+        "/abs/path",
+        "directory/../../evil",
+        "a/../b",
+    ] {
+        // bypass the checks of `insert_data` like a crafted file would
+        let mut pack = super::Package::from_file_info(
+            vec![],
+            super::PackageVersion::from(super::CURRENT_VERSION),
+            super::Compression::None,
+        );
+        pack.names.insert(name.to_string(), b"evil".to_vec());
+        super::write_package(out_file.clone(), &mut pack)?;
+        out_file.set_extension("m3pkg");
+        let res = super::load_package(out_file.clone());
+        assert!(
+            matches!(res, Err(UnpackError::UnsafePath { .. })),
+            "`{name}` was loaded"
+        );
+        assert!(super::PackageReader::open(out_file.clone()).is_err());
+        let res = super::unpack_to_dir(dest_tmp.path().join("unpacked"), &pack);
+        assert!(matches!(res, Err(UnpackError::UnsafePath { .. })));
+        let res = pack.insert_data(name.to_string(), b"evil".to_vec());
+        assert!(matches!(res, Err(super::err::InsertError::ProhibitedPath)));
+    }
+
+    // dots inside of a name are not a move to the parent directory
+    let src_tmp = tempdir::TempDir::new("dots_tmp")?;
+    std::fs::create_dir(src_tmp.path().join("dir..name"))?;
+    std::fs::write(src_tmp.path().join("v1..2.txt"), "dotted")?;
+    std::fs::write(src_tmp.path().join("dir..name/..hidden"), "hidden")?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    super::write_package(out_file.clone(), &mut pack)?;
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.get_data_ref("v1..2.txt"), Some(&b"dotted"[..]));
+    assert!(super::PackageReader::open(out_file.clone())?.has("dir..name/..hidden"));
+    assert!(super::MappedPackage::open(out_file)?.has("dir..name/..hidden"));
+    let unpacked = dest_tmp.path().join("dotted");
+    super::unpack_to_dir(unpacked.clone(), &loaded)?;
+    assert_eq!(
+        std::fs::read_to_string(unpacked.join("dir..name/..hidden"))?,
+        "hidden"
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unpack_through_symlinks() -> Result<(), Box<dyn Error>> {
+    use super::err::UnpackError;
+    use std::os::unix::fs::symlink;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    let outside_tmp = tempdir::TempDir::new("outside_tmp")?;
+    let outside_file = outside_tmp.path().join("file.txt");
+    std::fs::write(&outside_file, "untouched")?;
+
+    // a link in place of a directory entry is replaced by the directory
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    symlink(outside_tmp.path(), dest_tmp.path().join("directory"))?;
+    super::unpack_to_dir(dest_tmp.path().to_path_buf(), &pack)?;
+    assert!(!outside_tmp.path().join("text_file.txt").exists());
+    assert!(dest_tmp
+        .path()
+        .join("directory")
+        .symlink_metadata()?
+        .is_dir());
+
+    // a link in place of a parent directory is refused
+    pack.remove_data("directory");
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    symlink(outside_tmp.path(), dest_tmp.path().join("directory"))?;
+    let res = super::unpack_to_dir(dest_tmp.path().to_path_buf(), &pack);
+    assert!(matches!(res, Err(UnpackError::UnsafePath { .. })));
+    assert!(!outside_tmp.path().join("text_file.txt").exists());
+
+    // a link in place of a file is replaced instead of written through
+    let dest_tmp = tempdir::TempDir::new("dest_tmp")?;
+    symlink(&outside_file, dest_tmp.path().join("text_file.txt"))?;
+    super::unpack_to_dir(dest_tmp.path().to_path_buf(), &pack)?;
+    assert_eq!(std::fs::read_to_string(&outside_file)?, "untouched");
+    let unpacked = dest_tmp.path().join("text_file.txt");
+    assert!(!unpacked.symlink_metadata()?.file_type().is_symlink());
+    assert_eq!(std::fs::read_to_string(unpacked)?, "text");
+    Ok(())
+}
+
+/// Builds a package of version 0.0.1.0 by hand, with a data table listing `table` and followed
+/// by `data`
+fn raw_package(table: &[(&str, u64, u64)], data: &[u8]) -> Vec<u8> {
+    let mut buf = super::FILE_HEADER.to_vec();
+    buf.extend_from_slice(&[0, 0, 1, 0, 0, 0]);
+    for (name, index, size) in table {
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&index.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[test]
+fn test_malformed_packages() -> Result<(), Box<dyn Error>> {
+    use super::err::{ParseError, UnpackError};
+    let tmp = tempdir::TempDir::new("malformed_tmp")?;
+    let path = tmp.path().join("pack.m3pkg");
+    let load = |bytes: &[u8]| {
+        std::fs::write(&path, bytes).unwrap();
+        let res = super::load_package(path.clone());
+        // readers that load entries on demand check the table the same way
+        assert_eq!(
+            res.is_err(),
+            super::PackageReader::open(path.clone()).is_err()
+        );
+        assert_eq!(
+            res.is_err(),
+            super::MappedPackage::open(path.clone()).is_err()
+        );
+        res
+    };
+
+    let valid = raw_package(&[("a", 0, 3), ("b", 3, 2)], b"abcde");
+    let pack = load(&valid)?;
+    assert_eq!(pack.get_data("b"), Some(b"de".to_vec()));
+
+    // cut off in the middle of the size of `b` and right before the terminator
+    for len in [valid.len() - 10, valid.len() - 6] {
+        let res = load(&valid[..len]);
+        assert!(matches!(
+            res,
+            Err(UnpackError::ParseError(ParseError::Truncated { offset })) if offset == len as u64
+        ));
+    }
+    // the data of `b` starts 3 bytes into the data blob, which follows the 47 bytes in front
+    let res = load(&raw_package(&[("a", 0, 3), ("b", 3, 5)], b"abcde"));
+    assert!(matches!(
+        res,
+        Err(UnpackError::ParseError(ParseError::OutOfBounds { name, offset: 50, size: 5, data_end: 52 }))
+            if name == "b"
+    ));
+    let res = load(&raw_package(&[("a", u64::MAX, 2)], b"abcde"));
+    assert!(matches!(
+        res,
+        Err(UnpackError::ParseError(ParseError::OutOfBounds { .. }))
+    ));
+    let res = load(&raw_package(&[("a", 0, 3), ("b", 2, 3)], b"abcde"));
+    assert!(matches!(
+        res,
+        Err(UnpackError::ParseError(ParseError::OverlappingEntries {
+            offset: 49,
+            ..
+        }))
+    ));
+    let res = load(&raw_package(&[("a", 0, 3), ("a", 3, 2)], b"abcde"));
+    assert!(matches!(
+        res,
+        Err(UnpackError::ParseError(ParseError::DuplicateName { name, offset: 28 })) if name == "a"
+    ));
+
+    let mut not_a_package = valid.clone();
+    not_a_package[0] = b'X';
+    assert!(matches!(
+        load(&not_a_package),
+        Err(UnpackError::InvalidFile { offset: 0, .. })
+    ));
+    assert!(matches!(
+        load(&valid[..6]),
+        Err(UnpackError::InvalidFile { offset: 0, .. })
+    ));
+    let mut unknown_version = valid.clone();
+    unknown_version[4..8].copy_from_slice(&[9, 9, 9, 9]);
+    assert!(matches!(
+        load(&unknown_version),
+        Err(UnpackError::UnsupportedFormat(super::err::UnsupportedError::Version { version }))
+            if version.ver == (9, 9, 9, 9)
+    ));
+    // the name of `b` follows the 10 bytes of the header and the 18 bytes of `a`
+    let mut bad_name = valid.clone();
+    bad_name[28] = 0xFF;
+    assert!(matches!(
+        load(&bad_name),
+        Err(UnpackError::StringError { offset: 28, .. })
+    ));
+
+    // the type of the last entry sits in front of the table terminator and the footer
+    let mut pack = super::Package::from_file_info(
+        vec![],
+        super::PackageVersion::from(super::CURRENT_VERSION),
+        super::Compression::None,
+    );
+    pack.insert_data("entry".to_string(), b"data".to_vec())?;
+    let mut current = vec![];
+    pack.write_to(&mut current)?;
+    let kind_at = current.len() - super::parse::FOOTER_LEN as usize - 2;
+    current[kind_at] = 0x07;
+    let res = load(&current);
+    let Err(UnpackError::ParseError(ParseError::InvalidEntry { name, source, .. })) = res else {
+        panic!("unexpected result {res:?}");
+    };
+    assert_eq!(name, "entry");
+    assert!(matches!(*source, ParseError::EntryKind { kind: 0x07 }));
+
+    // the salt and costs of the key derivation start right after its type at offset 11
+    pack.set_encryption(Some(super::Encryption {
+        key: super::EncryptionKey::Passphrase("truncated".to_string()),
+        encrypt_table: false,
+    }));
+    let mut encrypted = vec![];
+    pack.write_to(&mut encrypted)?;
+    assert!(matches!(
+        load(&encrypted[..20]),
+        Err(UnpackError::ParseError(ParseError::Encryption {
+            offset: 12
+        }))
+    ));
+
+    // I/O errors name the file they happened on
+    let missing = tmp.path().join("missing");
+    let res = super::load_package(missing.clone());
+    assert!(matches!(res, Err(UnpackError::FileError { path, .. }) if path == missing));
+    let res = super::package_dir(missing.clone());
+    assert!(matches!(
+        res,
+        Err(super::err::PackingError::IoError { path, .. }) if path == missing
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_load_limits() -> Result<(), Box<dyn Error>> {
+    use super::err::{LimitError, UnpackError};
+    let tmp = tempdir::TempDir::new("limits_tmp")?;
+    let out_file = tmp.path().join("pack");
+    let mut pack = super::Package::from_file_info(
+        vec![],
+        super::PackageVersion::from(super::CURRENT_VERSION),
+        super::Compression::Zstd,
+    );
+    pack.insert_data("small".to_string(), b"tiny".to_vec())?;
+    pack.insert_data("zeros".to_string(), vec![0u8; 1 << 20])?;
+    super::write_package(out_file.clone(), &mut pack)?;
+    let path = out_file.with_extension("m3pkg");
+    // the compressed package is only a fraction of what it decompresses to
+    assert!(std::fs::metadata(&path)?.len() < 1 << 12);
+
+    let load = |limits: super::LoadLimits| {
+        let options = super::LoadOptions {
+            limits,
+            ..Default::default()
+        };
+        super::load_package_with(path.clone(), &options)
+    };
+    assert!(load(super::LoadLimits::default()).is_ok());
+    let limits = super::LoadLimits {
+        max_entries: 1,
+        ..Default::default()
+    };
+    assert!(matches!(
+        load(limits),
+        Err(UnpackError::LimitExceeded(LimitError::Entries(1)))
+    ));
+    let limits = super::LoadLimits {
+        max_name_len: 4,
+        ..Default::default()
+    };
+    assert!(matches!(
+        load(limits),
+        Err(UnpackError::LimitExceeded(LimitError::NameLength(4)))
+    ));
+    let limits = super::LoadLimits {
+        max_total_size: 1 << 19,
+        ..Default::default()
+    };
+    assert!(matches!(
+        load(limits),
+        Err(UnpackError::LimitExceeded(LimitError::TotalSize(_)))
+    ));
+
+    // the limit applies to every single entry read on demand
+    let options = super::LoadOptions {
+        limits,
+        ..Default::default()
+    };
+    let reader = super::PackageReader::open_with(path.clone(), &options)?;
+    assert_eq!(reader.get_data("small"), Some(b"tiny".to_vec()));
+    assert!(matches!(
+        reader.try_get_data("zeros"),
+        Err(UnpackError::LimitExceeded(LimitError::TotalSize(_)))
+    ));
+
+    // key derivation costs are read from the header and checked before they are spent
+    let passphrase = super::EncryptionKey::Passphrase("limits".to_string());
+    pack.set_encryption(Some(super::Encryption {
+        key: passphrase.clone(),
+        encrypt_table: false,
+    }));
+    super::write_package(out_file.clone(), &mut pack)?;
+    let mut bytes = std::fs::read(&path)?;
+    // magic, version, compression, flags, key derivation and salt come before the memory cost
+    bytes[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, bytes)?;
+    let res = super::load_package_encrypted(path.clone(), &passphrase);
+    assert!(matches!(
+        res,
+        Err(UnpackError::LimitExceeded(LimitError::KeyDerivation {
+            memory: u32::MAX,
+            ..
+        }))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_fuzz_corpus() -> Result<(), Box<dyn Error>> {
+    let corpus = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/load_package");
+    let mut replayed = 0;
+    for entry in std::fs::read_dir(corpus)? {
+        let path = entry?.path();
+        let bytes = std::fs::read(&path)?;
+        let name = path.file_name().unwrap().to_string_lossy();
+        // malformed packages are rejected with an error instead of a panic
+        let res = std::panic::catch_unwind(|| {
+            let loaded = super::load_package_from_bytes(&bytes);
+            let read = super::PackageReader::new(
+                io::Cursor::new(&bytes[..]),
+                &super::LoadOptions::default(),
+            );
+            (loaded.is_ok(), read.is_ok())
+        });
+        let Ok((loaded, read)) = res else {
+            panic!("`{name}` panicked");
+        };
+        if name.starts_with("valid-") {
+            assert!(loaded && read, "`{name}` was not loaded");
+        }
+        replayed += 1;
+    }
+    assert!(replayed > 0);
+    Ok(())
+}
+
+#[test]
+fn test_in_memory_packages() -> Result<(), Box<dyn Error>> {
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    pack.set_compression(super::Compression::Zstd);
+    let mut buf = vec![];
+    pack.write_to(&mut buf)?;
+
+    let from_bytes = super::Package::from_bytes(bytes::Bytes::from(buf.clone()))?;
+    let from_reader = super::Package::from_reader(&buf[..])?;
+    for loaded in [from_bytes, from_reader] {
+        assert_eq!(loaded.compression(), super::Compression::Zstd);
+        assert_eq!(loaded.get_files(), pack.get_files());
+        assert_eq!(loaded.directories(), pack.directories());
+    }
+
+    let key = super::EncryptionKey::Raw([7; 32]);
+    pack.set_encryption(Some(super::Encryption {
+        key: key.clone(),
+        encrypt_table: true,
+    }));
+    let mut buf = vec![];
+    pack.write_to(&mut buf)?;
+    assert!(super::Package::from_reader(&buf[..]).is_err());
+    let options = super::LoadOptions {
+        key: Some(key),
+        ..Default::default()
+    };
+    let loaded = super::Package::from_reader_with(&buf[..], &options)?;
+    assert_eq!(
+        loaded.get_data_ref("text_file.txt"),
+        pack.get_data_ref("text_file.txt")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reproducible_output() -> Result<(), Box<dyn Error>> {
+    let out_tmp = tempdir::TempDir::new("out_tmp")?;
+    let pack_hash = |src: &std::path::Path, options: &super::PackOptions, out: &str| {
+        let mut pack = super::package_dir_with(src.to_path_buf(), options).unwrap();
+        pack.set_compression(super::Compression::Zstd);
+        let out = out_tmp.path().join(out);
+        super::write_package(out.clone(), &mut pack).unwrap();
+        let packed = std::fs::read(out.with_extension("m3pkg")).unwrap();
+
+        let mut writer =
+            super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::Zstd, None)
+                .unwrap();
+        writer.add_dir_with(src.to_path_buf(), options).unwrap();
+        let streamed = writer.finish().unwrap().into_inner();
+        assert_eq!(packed, streamed);
+        crc32c::crc32c(&packed)
+    };
+
+    // names sorting differently by full name than directory by directory
+    let create_tree = || -> Result<TempDir, Box<dyn Error>> {
+        let tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+        std::fs::write(tmp.path().join("directory.txt"), "dot")?;
+        std::fs::write(tmp.path().join("directory-b"), "dash")?;
+        Ok(tmp)
+    };
+    let src_tmp = create_tree()?;
+    let options = super::PackOptions::default();
+    assert_eq!(
+        pack_hash(src_tmp.path(), &options, "first"),
+        pack_hash(src_tmp.path(), &options, "second")
+    );
+
+    // a copy of the tree made later only matches once the modification times are clamped
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let copy_tmp = create_tree()?;
+    assert_ne!(
+        pack_hash(src_tmp.path(), &options, "first"),
+        pack_hash(copy_tmp.path(), &options, "copy")
+    );
+    let options = super::PackOptions {
+        mtime_clamp: Some(1_000_000),
+        ..Default::default()
+    };
+    assert_eq!(
+        pack_hash(src_tmp.path(), &options, "first"),
+        pack_hash(copy_tmp.path(), &options, "copy")
+    );
+    let loaded = super::load_package(out_tmp.path().join("copy.m3pkg"))?;
+    assert_eq!(loaded.metadata("text_file.txt").unwrap().mtime, 1_000_000);
+    Ok(())
+}
+
+#[test]
+fn test_atomic_writes() -> Result<(), Box<dyn Error>> {
+    use super::err::WriteError;
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let out_tmp = tempdir::TempDir::new("out_tmp")?;
+    let out_file = out_tmp.path().join("pack.m3pkg");
+    let dir_listing = || {
+        let mut names: Vec<_> = std::fs::read_dir(out_tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+
+    let mut pack = super::package_dir(src_tmp.path().to_path_buf())?;
+    super::write_package(out_file.clone(), &mut pack)?;
+    let first = std::fs::read(&out_file)?;
+
+    // a writer dropped before it is finished leaves the previous package and no temporary file
+    let mut writer =
+        super::PackageWriter::create(out_file.clone(), super::Compression::Zstd, None)?;
+    writer.add_file("partial.txt", &b"partial"[..])?;
+    drop(writer);
+    assert_eq!(std::fs::read(&out_file)?, first);
+    assert_eq!(dir_listing(), ["pack.m3pkg"]);
+
+    let options = super::WriteOptions {
+        backup: true,
+        ..Default::default()
+    };
+    pack.set_compression(super::Compression::Zstd);
+    super::write_package_with(out_file.clone(), &mut pack, &options)?;
+    assert_eq!(dir_listing(), ["pack.m3pkg", "pack.m3pkg.bak"]);
+    assert_eq!(std::fs::read(out_tmp.path().join("pack.m3pkg.bak"))?, first);
+    let loaded = super::load_package(out_file.clone())?;
+    assert_eq!(loaded.compression(), super::Compression::Zstd);
+
+    // a destination that can not be replaced is reported with its path
+    let blocked = out_tmp.path().join("blocked.m3pkg");
+    std::fs::create_dir(&blocked)?;
+    std::fs::write(blocked.join("file"), "in the way")?;
+    let res = super::write_package(blocked.clone(), &mut pack);
+    assert!(matches!(res, Err(WriteError::IncompleteWrite { path, .. }) if path == blocked));
+    assert_eq!(
+        dir_listing(),
+        ["blocked.m3pkg", "pack.m3pkg", "pack.m3pkg.bak"]
+    );
+
+    // a package written into the directory it packages leaves itself out, also once it exists
+    let inner = src_tmp.path().join("inner.m3pkg");
+    for _ in 0..2 {
+        let mut writer =
+            super::PackageWriter::create(inner.clone(), super::Compression::None, None)?;
+        writer.add_dir(src_tmp.path().to_path_buf())?;
+        writer.finish()?;
+        let loaded = super::load_package(inner.clone())?;
+        assert_eq!(loaded.get_files().len(), pack.get_files().len());
+        assert!(loaded
+            .get_files()
+            .keys()
+            .all(|name| !name.contains("inner.m3pkg")));
+    }
+    Ok(())
+}
+
+/// Fills a package past the file size limit the parent test runs it with, only then is the
+/// variable set
+#[cfg(unix)]
+#[test]
+#[ignore]
+fn write_past_size_limit() -> Result<(), Box<dyn Error>> {
+    let Some(out_file) = std::env::var_os("M3_SIZE_LIMITED_OUT") else {
+        return Ok(());
+    };
+    let out_file = PathBuf::from(out_file);
+    let mut writer =
+        super::PackageWriter::create(out_file.clone(), super::Compression::None, None)?;
+    let res = writer.add_file("large.bin", &vec![0u8; 1 << 20][..]);
+    assert!(
+        matches!(&res, Err(super::err::WriteError::IncompleteWrite { path, .. }) if *path == out_file),
+        "unexpected result {res:?}"
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_failed_writes() -> Result<(), Box<dyn Error>> {
+    let out_tmp = tempdir::TempDir::new("out_tmp")?;
+    // the limit applies to the whole process, so the writing happens in a test of its own
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg("trap '' XFSZ; ulimit -f 64; exec \"$0\" --ignored --exact tests::write_past_size_limit")
+        .arg(std::env::current_exe()?)
+        .env("M3_SIZE_LIMITED_OUT", out_tmp.path().join("pack.m3pkg"))
+        .stdout(std::process::Stdio::null())
+        .status()?;
+    assert!(status.success());
+    // the partial package was removed again
+    assert_eq!(std::fs::read_dir(out_tmp.path())?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_ignore_patterns() -> Result<(), Box<dyn Error>> {
+    let src_tmp = tempdir::TempDir::new("ignore_tmp")?;
+    let root = src_tmp.path();
+    for (path, content) in [
+        (
+            ".m3ignore",
+            "# version control\n.git/\n*.swp\n/build\n\\#*\n",
+        ),
+        (".git/config", ""),
+        (".git/objects/ab", ""),
+        ("#notes", ""),
+        ("src/.m3ignore", "*.tmp\n!keep.tmp\n"),
+        ("src/main.rs", "fn main() {}"),
+        ("src/main.rs.swp", ""),
+        ("src/scratch.tmp", ""),
+        ("src/keep.tmp", ""),
+        ("build/out.bin", ""),
+        ("docs/build/notes.txt", ""),
+        ("assets/img/a.png", ""),
+        ("assets/img/b.jpg", ""),
+        ("assets/raw/c1.psd", ""),
+    ] {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content)?;
+    }
+    let sorted = |names: Vec<&String>| {
+        let mut names: Vec<_> = names.into_iter().map(|n| n.as_str()).collect();
+        names.sort();
+        names.join(" ")
+    };
+
+    let pack = super::package_dir(root.to_path_buf())?;
+    assert_eq!(
+        sorted(pack.get_files().keys().collect()),
+        ".m3ignore assets/img/a.png assets/img/b.jpg assets/raw/c1.psd docs/build/notes.txt \
+         src/.m3ignore src/keep.tmp src/main.rs"
+    );
+    assert_eq!(
+        sorted(pack.directories().iter().collect()),
+        "assets assets/img assets/raw docs docs/build src"
+    );
+
+    let options = super::PackOptions {
+        exclude: vec!["assets/**/*.jpg".to_string(), "c[0-9].*".to_string()],
+        ..Default::default()
+    };
+    let pack = super::package_dir_with(root.to_path_buf(), &options)?;
+    assert!(pack.has("assets/img/a.png"));
+    assert!(!pack.has("assets/img/b.jpg") && !pack.has("assets/raw/c1.psd"));
+
+    let options = super::PackOptions {
+        include: vec!["*.png".to_string(), "docs/".to_string()],
+        ..Default::default()
+    };
+    let pack = super::package_dir_with(root.to_path_buf(), &options)?;
+    assert_eq!(
+        sorted(pack.get_files().keys().collect()),
+        "assets/img/a.png docs/build/notes.txt"
+    );
+    assert_eq!(
+        sorted(pack.directories().iter().collect()),
+        "assets assets/img docs docs/build"
+    );
+
+    // the streaming writer packages the same entries
+    let mut writer =
+        super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::None, None)?;
+    writer.add_dir_with(root.to_path_buf(), &options)?;
+    let streamed = super::Package::from_bytes(writer.finish()?.into_inner().into())?;
+    assert_eq!(streamed.get_files(), pack.get_files());
+    assert_eq!(streamed.directories(), pack.directories());
+    Ok(())
+}
+
+#[test]
+fn test_pathological_patterns() -> Result<(), Box<dyn Error>> {
+    let src_tmp = tempdir::TempDir::new("glob_tmp")?;
+    let name = "a".repeat(60);
+    std::fs::write(src_tmp.path().join(&name), "")?;
+    std::fs::write(
+        src_tmp.path().join(".m3ignore"),
+        "*a*a*a*a*a*a*a*a*a*a*b
+",
+    )?;
+    let options = super::PackOptions {
+        exclude: vec![
+            "**/*a*a*a*a*a*a*a*a*a*a*a*a*/**".to_string(),
+            "*a*a*a*a*a*a*a*b".to_string(),
+        ],
+        ..Default::default()
+    };
+    let started = std::time::Instant::now();
+    let pack = super::package_dir_with(src_tmp.path().to_path_buf(), &options)?;
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(pack.has(&name));
+
+    let options = super::PackOptions {
+        exclude: vec!["*a*a*a*a*a*a*a*a*a*a*a".to_string()],
+        ..Default::default()
+    };
+    let pack = super::package_dir_with(src_tmp.path().to_path_buf(), &options)?;
+    assert!(!pack.has(&name));
+    Ok(())
+}
+
+#[test]
+fn test_parallel_packing() -> Result<(), Box<dyn Error>> {
+    let src_tmp = tempdir::TempDir::new("src_tmp")?;
+    let out_tmp = tempdir::TempDir::new("out_tmp")?;
+    for dir in 0..8 {
+        let dir_path = src_tmp.path().join(format!("dir{dir}"));
+        std::fs::create_dir(&dir_path)?;
+        for file in 0..40 {
+            let text = format!("file {file} of directory {dir}\n").repeat(file * 50);
+            std::fs::write(dir_path.join(format!("file{file}.txt")), text)?;
+        }
+    }
+    std::fs::create_dir(src_tmp.path().join("empty"))?;
+    std::fs::write(src_tmp.path().join("dir1.txt"), "dot")?;
+    std::fs::write(src_tmp.path().join("dir1-b"), "dash")?;
+    let options = super::PackOptions {
+        mtime_clamp: Some(1_000_000),
+        ..Default::default()
+    };
+    let pack_with = |threads: usize| -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let options = super::PackOptions {
+            threads,
+            ..options.clone()
+        };
+        let mut pack = super::package_dir_with(src_tmp.path().to_path_buf(), &options)?;
+        pack.set_compression(super::Compression::Zstd);
+        let out = out_tmp.path().join(format!("pack{threads}.m3pkg"));
+        let write_options = super::WriteOptions {
+            threads,
+            ..Default::default()
+        };
+        super::write_package_with(out.clone(), &mut pack, &write_options)?;
+        let packed = std::fs::read(out)?;
+
+        let mut writer =
+            super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::Zstd, None)?;
+        writer.add_dir_with(src_tmp.path().to_path_buf(), &options)?;
+        Ok((packed, writer.finish()?.into_inner()))
+    };
+
+    let (sequential, streamed) = pack_with(1)?;
+    assert_eq!(sequential, streamed);
+    for threads in [0, 2, 7] {
+        assert_eq!(pack_with(threads)?, (sequential.clone(), streamed.clone()));
+    }
+    let loaded = super::Package::from_bytes(sequential.into())?;
+    assert_eq!(loaded.get_files().len(), 8 * 40 + 2);
+    assert!(loaded.directories().contains("empty"));
+
+    // the first error stops the threads and is returned
+    let mut writer =
+        super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::Zstd, None)?;
+    writer.add_file("dir3/file5.txt", &b"taken"[..])?;
+    let options = super::PackOptions {
+        threads: 4,
+        ..Default::default()
+    };
+    assert!(matches!(
+        writer.add_dir_with(src_tmp.path().to_path_buf(), &options),
+        Err(super::err::WriteError::DuplicateEntry { name }) if name == "dir3/file5.txt"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_progress_and_cancellation() -> Result<(), Box<dyn Error>> {
+    use super::err::{PackingError, UnpackError, WriteError};
+    /// Records the progress reported and cancels after `cancel_after` entries
+    #[derive(Default)]
+    struct Recorder {
+        total: Option<(usize, u64)>,
+        started: Vec<String>,
+        finished: Vec<String>,
+        bytes: u64,
+        cancel: super::CancellationToken,
+        cancel_after: Option<usize>,
+    }
+    impl super::ProgressSink for Recorder {
+        fn total(&mut self, entries: usize, bytes: u64) {
+            assert!(self.total.is_none() && self.started.is_empty());
+            self.total = Some((entries, bytes));
+        }
+        fn entry_started(&mut self, name: &str) {
+            assert_eq!(self.started.len(), self.finished.len());
+            self.started.push(name.to_string());
+        }
+        fn bytes_processed(&mut self, bytes: u64) {
+            self.bytes += bytes;
+        }
+        fn entry_finished(&mut self, name: &str) {
+            assert_eq!(self.started.last().map(String::as_str), Some(name));
+            self.finished.push(name.to_string());
+            if self.cancel_after == Some(self.finished.len()) {
+                self.cancel.cancel();
+            }
+        }
+    }
+    impl Recorder {
+        fn assert_complete(&self) {
+            let (entries, bytes) = self.total.unwrap();
+            assert_eq!(self.finished.len(), entries);
+            assert_eq!(self.bytes, bytes);
+        }
+    }
+
+    let src_tmp = create_test_directory(&PACKING_TEST_MODEL)?;
+    let out_tmp = tempdir::TempDir::new("out_tmp")?;
+    let mut progress = Recorder::default();
+    let options = super::PackOptions::default();
+    let mut pack =
+        super::package_dir_with_progress(src_tmp.path().to_path_buf(), &options, &mut progress)?;
+    progress.assert_complete();
+    assert_eq!(progress.total.unwrap().0, PACKING_TEST_MODEL.len());
+
+    let out_file = out_tmp.path().join("pack.m3pkg");
+    let mut progress = Recorder::default();
+    let options = super::WriteOptions::default();
+    super::write_package_with_progress(out_file.clone(), &mut pack, &options, &mut progress)?;
+    progress.assert_complete();
+    assert!(progress.finished.is_sorted());
+
+    let mut progress = Recorder::default();
+    let mut writer =
+        super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::Zstd, None)?;
+    let options = super::PackOptions::default();
+    writer.add_dir_with_progress(src_tmp.path().to_path_buf(), &options, &mut progress)?;
+    progress.assert_complete();
+
+    let unpack_dir = out_tmp.path().join("unpacked");
+    let mut progress = Recorder::default();
+    let options = super::UnpackOptions::default();
+    super::unpack_to_dir_with_progress(unpack_dir.clone(), &pack, &options, &mut progress)?;
+    progress.assert_complete();
+
+    // a cancelled token stops every operation
+    let cancelled = super::CancellationToken::new();
+    cancelled.cancel();
+    let options = super::PackOptions {
+        cancel: cancelled.clone(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        super::package_dir_with(src_tmp.path().to_path_buf(), &options),
+        Err(PackingError::Cancelled)
+    ));
+    let mut writer =
+        super::PackageWriter::new(io::Cursor::new(vec![]), super::Compression::None, None)?;
+    assert!(matches!(
+        writer.add_dir_with(src_tmp.path().to_path_buf(), &options),
+        Err(WriteError::PackingError(PackingError::Cancelled))
+    ));
+    let options = super::WriteOptions {
+        cancel: cancelled.clone(),
+        ..Default::default()
+    };
+    let first = std::fs::read(&out_file)?;
+    assert!(matches!(
+        super::write_package_with(out_file.clone(), &mut pack, &options),
+        Err(WriteError::Cancelled)
+    ));
+    assert_eq!(std::fs::read(&out_file)?, first);
+
+    // cancelling while unpacking leaves the entries unpacked so far
+    let mut progress = Recorder {
+        cancel_after: Some(2),
+        ..Default::default()
+    };
+    let options = super::UnpackOptions {
+        cancel: progress.cancel.clone(),
+        ..Default::default()
+    };
+    let unpack_dir = out_tmp.path().join("cancelled");
+    assert!(matches!(
+        super::unpack_to_dir_with_progress(unpack_dir, &pack, &options, &mut progress),
+        Err(UnpackError::Cancelled)
+    ));
+    assert_eq!(progress.finished.len(), 2);
+    Ok(())
+}
@@ -0,0 +1,536 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write};
+use std::path::PathBuf;
+
+use crate::atomic::AtomicFile;
+use crate::encryption::{Cipher, KeyDerivation, ENTRIES_ENCRYPTED, TABLE_ENCRYPTED};
+use crate::err::{self, WriteError};
+use crate::package::{validate_name, EntryKind, EntryMetadata};
+use crate::parse::{Header, FOOTER_MAGIC};
+use crate::progress::{NoProgress, ProgressSink};
+use crate::{
+    Compression, Encryption, EntryInfo, PackOptions, PackageVersion, WriteOptions, CURRENT_VERSION,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Builds the header of a new package and the cipher its entries are encrypted with
+pub(crate) fn new_header(
+    version: PackageVersion,
+    compression: Compression,
+    encryption: Option<&Encryption>,
+) -> Result<(Header, Option<Cipher>), WriteError> {
+    if !version.is_supported() {
+        return Err(err::UnsupportedError::Version { version }.into());
+    }
+    if compression != Compression::None && !version.supports_compression() {
+        return Err(err::UnsupportedError::Compression.into());
+    }
+    let mut header = Header {
+        version,
+        compression,
+        flags: 0,
+        kdf: None,
+    };
+    let mut cipher = None;
+    if let Some(encryption) = encryption {
+        if !version.supports_encryption() {
+            return Err(err::UnsupportedError::Encryption.into());
+        }
+        let kdf = KeyDerivation::new(&encryption.key);
+        cipher = Some(kdf.derive(&encryption.key)?);
+        header.flags = match encryption.encrypt_table {
+            true => ENTRIES_ENCRYPTED | TABLE_ENCRYPTED,
+            false => ENTRIES_ENCRYPTED,
+        };
+        header.kdf = Some(kdf);
+    }
+    Ok((header, cipher))
+}
+
+/// Compresses and encrypts the data of an entry, returning the bytes to store and how they are
+/// stored
+pub(crate) fn encode_entry(
+    name: &str,
+    data: &[u8],
+    version: PackageVersion,
+    compression: Compression,
+    level: i32,
+    cipher: Option<&Cipher>,
+    kind: EntryKind,
+) -> Result<(Vec<u8>, EntryInfo), WriteError> {
+    let mut compression = compression;
+    let mut stored = compression.compress(data, level)?;
+    if version.has_entry_codecs() && stored.len() >= data.len() {
+        // compressing did not help, store the entry as is
+        compression = Compression::None;
+        stored = data.to_vec();
+    }
+    if let Some(cipher) = cipher {
+        stored = cipher.encrypt(&stored, name.as_bytes())?;
+    }
+    let info = EntryInfo {
+        compression,
+        stored_size: stored.len() as u64,
+        original_size: data.len() as u64,
+        checksum: version.has_checksums().then(|| crc32c::crc32c(data)),
+        kind,
+    };
+    Ok((stored, info))
+}
+
+/// Appends the data table record of an entry stored at `index` of the data blob
+pub(crate) fn write_table_entry(
+    table: &mut Vec<u8>,
+    name: &str,
+    version: PackageVersion,
+    index: u64,
+    info: &EntryInfo,
+    metadata: Option<EntryMetadata>,
+    kind: EntryKind,
+) -> Result<(), WriteError> {
+    if kind != EntryKind::File && !version.has_entry_kinds() {
+        return Err(err::UnsupportedError::EntryKind.into());
+    }
+    // older versions only have room for 32-bit offsets and sizes, checked before anything is
+    // appended so the table stays intact
+    let too_large = || WriteError::EntryTooLarge {
+        name: name.to_string(),
+        version,
+    };
+    let narrow = match version.has_wide_table() {
+        true => None,
+        false => Some((
+            u32::try_from(index).map_err(|_| too_large())?,
+            u32::try_from(info.stored_size).map_err(|_| too_large())?,
+        )),
+    };
+    table.extend_from_slice(name.as_bytes());
+    table.push(0x00);
+    match narrow {
+        Some((index, size)) => {
+            table.extend_from_slice(&index.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+        }
+        None => {
+            table.extend_from_slice(&index.to_le_bytes());
+            table.extend_from_slice(&info.stored_size.to_le_bytes());
+        }
+    }
+    if version.has_entry_codecs() {
+        table.extend_from_slice(&info.original_size.to_le_bytes());
+        let codec: [u8; 2] = info.compression.into();
+        table.extend_from_slice(&codec);
+    }
+    if let Some(checksum) = info.checksum {
+        table.extend_from_slice(&checksum.to_le_bytes());
+    }
+    if version.has_metadata() {
+        match metadata {
+            Some(metadata) => {
+                table.push(0x01);
+                table.extend_from_slice(&metadata.to_bytes());
+            }
+            None => table.push(0x00),
+        }
+    }
+    if version.has_entry_kinds() {
+        table.push(kind.into());
+    }
+    Ok(())
+}
+
+/// Terminates the data table and encrypts it if the header asks for it
+pub(crate) fn seal_table(
+    mut table: Vec<u8>,
+    header: &Header,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<u8>, WriteError> {
+    table.push(0x00);
+    match cipher {
+        Some(cipher) if header.flags & TABLE_ENCRYPTED != 0 => {
+            // the header is authenticated along with the table
+            let table = cipher.encrypt(&table, &header.to_bytes())?;
+            let mut sealed = (table.len() as u64).to_le_bytes().to_vec();
+            sealed.extend_from_slice(&table);
+            Ok(sealed)
+        }
+        _ => Ok(table),
+    }
+}
+
+/// The footer pointing at a data table that starts `table_offset` bytes after the start of the
+/// data blob
+pub(crate) fn footer(table_offset: u64) -> Vec<u8> {
+    let mut footer = table_offset.to_le_bytes().to_vec();
+    footer.extend_from_slice(&FOOTER_MAGIC);
+    footer
+}
+
+/// Writes a package entry by entry without holding it in memory. The data table is kept until
+/// [`PackageWriter::finish`] writes it behind the data, so packages are always written as the
+/// current version
+pub struct PackageWriter<W: Write + Seek> {
+    writer: W,
+    header: Header,
+    cipher: Option<Cipher>,
+    compression_level: i32,
+    /// Position of the data blob inside of the writer
+    data_start: u64,
+    table: Vec<u8>,
+    names: HashSet<String>,
+    /// The file that replaces the destination once the package is finished
+    atomic: Option<AtomicFile>,
+}
+
+impl PackageWriter<BufWriter<fs::File>> {
+    /// Creates a .m3pkg file at `path` and writes its header. The package is written to a
+    /// temporary file that only replaces an existing package at `path` once it is finished
+    pub fn create(
+        path: PathBuf,
+        compression: Compression,
+        encryption: Option<Encryption>,
+    ) -> Result<Self, WriteError> {
+        Self::create_with(path, compression, encryption, &WriteOptions::default())
+    }
+    pub fn create_with(
+        mut path: PathBuf,
+        compression: Compression,
+        encryption: Option<Encryption>,
+        options: &WriteOptions,
+    ) -> Result<Self, WriteError> {
+        path.set_extension("m3pkg");
+        let atomic = AtomicFile::create(path, options.backup)?;
+        let handle = atomic.handle().map_err(|e| atomic.incomplete(e))?;
+        let mut writer =
+            Self::new(BufWriter::new(handle), compression, encryption).map_err(|e| match e {
+                WriteError::IoError(e) => atomic.incomplete(e),
+                e => e,
+            })?;
+        writer.atomic = Some(atomic);
+        Ok(writer)
+    }
+}
+
+impl<W: Write + Seek> PackageWriter<W> {
+    /// Writes the header of a package to the current position of `writer`
+    pub fn new(
+        mut writer: W,
+        compression: Compression,
+        encryption: Option<Encryption>,
+    ) -> Result<Self, WriteError> {
+        let version = PackageVersion::from(CURRENT_VERSION);
+        let (header, cipher) = new_header(version, compression, encryption.as_ref())?;
+        writer.write_all(&header.to_bytes())?;
+        let data_start = writer.stream_position()?;
+        Ok(Self {
+            writer,
+            header,
+            cipher,
+            compression_level: 0,
+            data_start,
+            table: vec![],
+            names: HashSet::new(),
+            atomic: None,
+        })
+    }
+    /// Sets the level used by the compression codec for the entries added from now on, 0 selects
+    /// the default level of the codec
+    pub fn set_compression_level(&mut self, level: i32) {
+        self.compression_level = level;
+    }
+    /// Adds an entry with the contents of `reader`. Uncompressed entries of unencrypted packages
+    /// are copied through in chunks, all other entries are held in memory while they are encoded
+    pub fn add_file(&mut self, name: &str, reader: impl Read) -> Result<(), WriteError> {
+        self.add_file_with_metadata(name, reader, None)
+    }
+    /// Adds an entry like [`PackageWriter::add_file`] and stores `metadata` along with it
+    pub fn add_file_with_metadata(
+        &mut self,
+        name: &str,
+        reader: impl Read,
+        metadata: Option<EntryMetadata>,
+    ) -> Result<(), WriteError> {
+        self.add_entry(name, reader, metadata, EntryKind::File)
+    }
+    /// Adds a directory entry, needed for directories without any files in them
+    pub fn add_dir_entry(
+        &mut self,
+        name: &str,
+        metadata: Option<EntryMetadata>,
+    ) -> Result<(), WriteError> {
+        self.add_entry(
+            name.trim_end_matches('/'),
+            &[][..],
+            metadata,
+            EntryKind::Directory,
+        )
+    }
+    /// Adds a symlink entry pointing to `target`
+    pub fn add_symlink(&mut self, name: &str, target: &str) -> Result<(), WriteError> {
+        if target.is_empty() {
+            return Err(err::InsertError::NotAFilePath.into());
+        }
+        self.add_entry(name, target.as_bytes(), None, EntryKind::Symlink)
+    }
+    fn add_entry(
+        &mut self,
+        name: &str,
+        mut reader: impl Read,
+        metadata: Option<EntryMetadata>,
+        kind: EntryKind,
+    ) -> Result<(), WriteError> {
+        if self.streams_entries() {
+            return self.stream_entry(name, reader, metadata, kind);
+        }
+        self.check_entry(name, kind)?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let (stored, info) = encode_entry(
+            name,
+            &data,
+            self.header.version,
+            self.header.compression,
+            self.compression_level,
+            self.cipher.as_ref(),
+            kind,
+        )?;
+        self.add_encoded(name, &stored, &info, metadata, kind)
+    }
+    /// Whether entries are copied through in chunks instead of being encoded in memory
+    fn streams_entries(&self) -> bool {
+        self.header.compression == Compression::None && self.cipher.is_none()
+    }
+    fn check_entry(&self, name: &str, kind: EntryKind) -> Result<(), WriteError> {
+        validate_name(name)?;
+        if self.names.contains(name) {
+            return Err(WriteError::DuplicateEntry {
+                name: name.to_string(),
+            });
+        }
+        if kind != EntryKind::File && !self.header.version.has_entry_kinds() {
+            return Err(err::UnsupportedError::EntryKind.into());
+        }
+        Ok(())
+    }
+    /// Copies an entry through in chunks, for packages whose entries are stored as they are
+    fn stream_entry(
+        &mut self,
+        name: &str,
+        mut reader: impl Read,
+        metadata: Option<EntryMetadata>,
+        kind: EntryKind,
+    ) -> Result<(), WriteError> {
+        self.check_entry(name, kind)?;
+        // offsets are taken from the writer so a failed entry only leaves unreferenced bytes
+        let index = self.position()? - self.data_start;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut checksum = 0;
+        let mut size = 0;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            checksum = crc32c::crc32c_append(checksum, &buf[..n]);
+            self.writer
+                .write_all(&buf[..n])
+                .map_err(|e| self.output_error(e))?;
+            size += n as u64;
+        }
+        let info = EntryInfo {
+            compression: Compression::None,
+            stored_size: size,
+            original_size: size,
+            checksum: Some(checksum),
+            kind,
+        };
+        let version = self.header.version;
+        write_table_entry(&mut self.table, name, version, index, &info, metadata, kind)?;
+        self.names.insert(name.to_string());
+        Ok(())
+    }
+    /// Writes an entry already encoded by [`encode_entry`]
+    fn add_encoded(
+        &mut self,
+        name: &str,
+        stored: &[u8],
+        info: &EntryInfo,
+        metadata: Option<EntryMetadata>,
+        kind: EntryKind,
+    ) -> Result<(), WriteError> {
+        self.check_entry(name, kind)?;
+        let index = self.position()? - self.data_start;
+        self.writer
+            .write_all(stored)
+            .map_err(|e| self.output_error(e))?;
+        let version = self.header.version;
+        write_table_entry(&mut self.table, name, version, index, info, metadata, kind)?;
+        self.names.insert(name.to_string());
+        Ok(())
+    }
+    /// Adds every file inside of `dir_path` and its subdirectories along with their metadata,
+    /// named by their path relative to `dir_path`
+    pub fn add_dir(&mut self, dir_path: PathBuf) -> Result<(), WriteError> {
+        self.add_dir_with(dir_path, &PackOptions::default())
+    }
+    /// Adds the contents of a directory like [`PackageWriter::add_dir`], as `options` ask for
+    pub fn add_dir_with(
+        &mut self,
+        dir_path: PathBuf,
+        options: &PackOptions,
+    ) -> Result<(), WriteError> {
+        self.add_dir_with_progress(dir_path, options, &mut NoProgress)
+    }
+    /// Adds the contents of a directory like [`PackageWriter::add_dir_with`], reporting every
+    /// entry written to `progress`
+    pub fn add_dir_with_progress(
+        &mut self,
+        dir_path: PathBuf,
+        options: &PackOptions,
+        progress: &mut dyn ProgressSink,
+    ) -> Result<(), WriteError> {
+        enum Prepared {
+            /// A file copied through while it is written, along with its size
+            Stream(String, fs::File, u64, EntryMetadata),
+            Encoded {
+                name: String,
+                stored: Vec<u8>,
+                info: EntryInfo,
+                metadata: Option<EntryMetadata>,
+                kind: EntryKind,
+            },
+        }
+
+        let dir_path = fs::canonicalize(&dir_path).map_err(err::PackingError::io(&dir_path))?;
+        // the package may be written into the directory it packages
+        let own_files = match &self.atomic {
+            Some(atomic) => atomic.own_files()?,
+            None => vec![],
+        };
+        let found = crate::collect_files(&dir_path, options, own_files)?;
+        let size = crate::files_size(&found)?;
+        progress.total(found.len(), size);
+        let streams = self.streams_entries();
+        let (version, compression) = (self.header.version, self.header.compression);
+        let level = self.compression_level;
+        // the cipher is lent to the threads encoding the entries while the writer writes them
+        let cipher = self.cipher.take();
+        let encode = |name: String, data: &[u8], metadata, kind| {
+            let (stored, info) = encode_entry(
+                &name,
+                data,
+                version,
+                compression,
+                level,
+                cipher.as_ref(),
+                kind,
+            )?;
+            Ok(Prepared::Encoded {
+                name,
+                stored,
+                info,
+                metadata,
+                kind,
+            })
+        };
+        // files are read and encoded on several threads, but written in the order they were found
+        let res = crate::parallel::map_ordered(
+            found,
+            options.threads,
+            |found| -> Result<Prepared, WriteError> {
+                if options.cancel.is_cancelled() {
+                    return Err(err::PackingError::Cancelled.into());
+                }
+                match found {
+                    crate::Found::File(path) => {
+                        let name = crate::entry_name(&dir_path, &path)?;
+                        let mut file =
+                            fs::File::open(&path).map_err(err::PackingError::io(&path))?;
+                        let metadata = file.metadata().map_err(err::PackingError::io(&path))?;
+                        let size = metadata.len();
+                        let metadata = options.entry_metadata(&metadata);
+                        if streams {
+                            return Ok(Prepared::Stream(name, file, size, metadata));
+                        }
+                        let mut data = vec![];
+                        file.read_to_end(&mut data)
+                            .map_err(err::PackingError::io(&path))?;
+                        encode(name, &data, Some(metadata), EntryKind::File)
+                    }
+                    crate::Found::Symlink(path) => {
+                        let name = crate::entry_name(&dir_path, &path)?;
+                        let target = crate::link_target(&path)?;
+                        if target.is_empty() {
+                            return Err(err::InsertError::NotAFilePath.into());
+                        }
+                        encode(name, target.as_bytes(), None, EntryKind::Symlink)
+                    }
+                    crate::Found::Directory(path) => {
+                        let name = crate::entry_name(&dir_path, &path)?;
+                        let metadata = fs::metadata(&path).map_err(err::PackingError::io(&path))?;
+                        let metadata = Some(options.entry_metadata(&metadata));
+                        encode(name, &[], metadata, EntryKind::Directory)
+                    }
+                }
+            },
+            |prepared| {
+                match prepared {
+                    Prepared::Stream(name, file, size, metadata) => {
+                        progress.entry_started(&name);
+                        let reader = BufReader::new(file);
+                        self.stream_entry(&name, reader, Some(metadata), EntryKind::File)?;
+                        progress.bytes_processed(size);
+                        progress.entry_finished(&name);
+                    }
+                    Prepared::Encoded {
+                        name,
+                        stored,
+                        info,
+                        metadata,
+                        kind,
+                    } => {
+                        progress.entry_started(&name);
+                        self.add_encoded(&name, &stored, &info, metadata, kind)?;
+                        progress.bytes_processed(info.original_size);
+                        progress.entry_finished(&name);
+                    }
+                }
+                Ok(())
+            },
+        );
+        self.cipher = cipher;
+        res
+    }
+    /// Writes the data table and the footer pointing to it, returning the underlying writer
+    pub fn finish(mut self) -> Result<W, WriteError> {
+        let table_start = self.position()?;
+        let table = std::mem::take(&mut self.table);
+        let table = seal_table(table, &self.header, self.cipher.as_ref())?;
+        let mut trailer = table;
+        trailer.extend_from_slice(&footer(table_start - self.data_start));
+        self.writer
+            .write_all(&trailer)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| self.output_error(e))?;
+        if let Some(atomic) = self.atomic.take() {
+            atomic.commit()?;
+        }
+        Ok(self.writer)
+    }
+    fn position(&mut self) -> Result<u64, WriteError> {
+        self.writer
+            .stream_position()
+            .map_err(|e| self.output_error(e))
+    }
+    /// An error writing the package, naming the destination file when the writer created it
+    fn output_error(&self, e: std::io::Error) -> WriteError {
+        match &self.atomic {
+            Some(atomic) => atomic.incomplete(e),
+            None => e.into(),
+        }
+    }
+}